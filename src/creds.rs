@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::ptr;
+use std::rc::Rc;
+
+use fuse::{FileAttr, FileType, Request};
+use gfapi_sys::glfs::{glfs_setfsgid, glfs_setfsgroups, glfs_setfsuid};
+use libc::{c_int, gid_t, size_t, uid_t, X_OK};
+use time::{self, Duration, Timespec};
+
+use error::last_errno;
+
+/// Who a gluster call is made on behalf of.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub uid: u32,
+    pub gid: u32,
+    /// Supplementary groups
+    pub groups: Vec<u32>,
+}
+
+impl Credentials {
+    /// The mount daemon itself
+    pub fn of_daemon() -> Credentials {
+        let pid = unsafe { ::libc::getpid() } as u32;
+        Credentials {
+            uid: unsafe { ::libc::geteuid() } as u32,
+            gid: unsafe { ::libc::getegid() } as u32,
+            groups: process_groups(pid).unwrap_or_default(),
+        }
+    }
+
+    /// Whether access(2) with `mask` would succeed on a file with these
+    /// attributes.  Only the owner, group or other bits that apply are
+    /// consulted, never a combination.  Root may read and write anything and
+    /// execute anything that is a directory or has an execute bit set.
+    pub fn may_access(&self, attr: &FileAttr, mask: u32) -> bool {
+        let wanted = (mask & 0o7) as u16;
+        if self.uid == 0 {
+            let executable = attr.kind == FileType::Directory || attr.perm & 0o111 != 0;
+            return wanted & X_OK as u16 == 0 || executable;
+        }
+        let granted = if self.uid == attr.uid {
+            (attr.perm >> 6) & 0o7
+        } else if self.gid == attr.gid || self.groups.contains(&attr.gid) {
+            (attr.perm >> 3) & 0o7
+        } else {
+            attr.perm & 0o7
+        };
+        granted & wanted == wanted
+    }
+}
+
+/// Supplementary groups of a running process
+fn process_groups(pid: u32) -> Option<Vec<u32>> {
+    let mut status = String::new();
+    File::open(format!("/proc/{}/status", pid))
+        .and_then(|mut f| f.read_to_string(&mut status))
+        .ok()?;
+    Some(groups_from_status(&status))
+}
+
+/// Pull the "Groups:" line out of /proc/<pid>/status
+fn groups_from_status(status: &str) -> Vec<u32> {
+    status.lines()
+        .find(|line| line.starts_with("Groups:"))
+        .map(|line| {
+            line["Groups:".len()..]
+                .split_whitespace()
+                .filter_map(|gid| gid.parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Who the callers of requests are, and the means to make gluster calls as
+/// them.
+pub struct Callers {
+    switch: Rc<dyn CredentialSwitch>,
+    daemon: Credentials,
+    /// Supplementary groups by pid, and when they were read
+    groups: HashMap<u32, (Timespec, Vec<u32>)>,
+    /// How long the groups read for a pid are trusted, like the C client's
+    /// gid-timeout
+    gid_timeout: Timespec,
+}
+
+impl Callers {
+    pub fn new(switch: Rc<dyn CredentialSwitch>,
+               daemon: Credentials,
+               gid_timeout: Timespec)
+               -> Callers {
+        Callers {
+            switch: switch,
+            daemon: daemon,
+            groups: HashMap::new(),
+            gid_timeout: gid_timeout,
+        }
+    }
+
+    /// The process that made a FUSE request.  The kernel only passes the uid,
+    /// gid and pid, so the supplementary groups are read from /proc.  A caller
+    /// that already exited gets none.
+    pub fn of_request(&mut self, req: &Request) -> Credentials {
+        self.credentials(req.uid(), req.gid(), req.pid(), time::get_time())
+    }
+
+    /// Make gluster calls as `caller` until the guard is dropped
+    pub fn switch_to(&self, caller: &Credentials) -> Result<Guard, c_int> {
+        Guard::new(self.switch.clone(), caller, self.daemon.clone())
+    }
+
+    fn credentials(&mut self, uid: u32, gid: u32, pid: u32, now: Timespec) -> Credentials {
+        let max_age = Duration::seconds(self.gid_timeout.sec) +
+                      Duration::nanoseconds(self.gid_timeout.nsec as i64);
+        let groups = match self.groups.get(&pid) {
+            Some(&(read_at, ref groups)) if now - read_at < max_age => Some(groups.clone()),
+            _ => None,
+        };
+        let groups = groups.unwrap_or_else(|| {
+            // Pids get reused, so anything stale goes rather than piling up
+            self.groups.retain(|_, &mut (read_at, _)| now - read_at < max_age);
+            let groups = process_groups(pid).unwrap_or_default();
+            self.groups.insert(pid, (now, groups.clone()));
+            groups
+        });
+        Credentials {
+            uid: uid,
+            gid: gid,
+            groups: groups,
+        }
+    }
+}
+
+/// Switches the credentials the calling thread's gluster calls are made with.
+pub trait CredentialSwitch {
+    fn switch(&self, creds: &Credentials) -> Result<(), c_int>;
+}
+
+/// gfapi's per-thread fsuid/fsgid/groups, which the server checks
+/// permissions and charges quota against.
+pub struct Gfapi;
+
+impl CredentialSwitch for Gfapi {
+    fn switch(&self, creds: &Credentials) -> Result<(), c_int> {
+        let groups = if creds.groups.is_empty() {
+            ptr::null()
+        } else {
+            creds.groups.as_ptr() as *const gid_t
+        };
+        unsafe {
+            if glfs_setfsuid(creds.uid as uid_t) < 0 || glfs_setfsgid(creds.gid as gid_t) < 0 ||
+               glfs_setfsgroups(creds.groups.len() as size_t, groups) < 0 {
+                return Err(last_errno());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Gluster calls run as a request's caller while this is alive.  Dropping it
+/// switches back to the daemon's own credentials.
+pub struct Guard {
+    switch: Rc<dyn CredentialSwitch>,
+    caller: Credentials,
+    restore: Credentials,
+}
+
+impl Guard {
+    pub fn new(switch: Rc<dyn CredentialSwitch>,
+               caller: &Credentials,
+               restore: Credentials)
+               -> Result<Guard, c_int> {
+        if let Err(errno) = switch.switch(caller) {
+            // Don't leave half of the caller's identity behind
+            let _ = switch.switch(&restore);
+            return Err(errno);
+        }
+        Ok(Guard {
+            switch: switch,
+            caller: caller.clone(),
+            restore: restore,
+        })
+    }
+
+    /// Who calls are being made as
+    pub fn caller(&self) -> &Credentials {
+        &self.caller
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if let Err(errno) = self.switch.switch(&self.restore) {
+            error!("Unable to restore credentials {:?}: errno {}",
+                   self.restore,
+                   errno);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use fuse::{FileAttr, FileType};
+    use libc::{c_int, EPERM, F_OK, R_OK, W_OK, X_OK};
+    use time::{Duration, Timespec};
+
+    use super::{groups_from_status, process_groups, Callers, CredentialSwitch, Credentials,
+                Guard};
+    use testutil;
+
+    /// Records every switch instead of making it
+    #[derive(Default)]
+    struct Recorder {
+        seen: RefCell<Vec<Credentials>>,
+        fail_uid: Option<u32>,
+    }
+
+    impl CredentialSwitch for Recorder {
+        fn switch(&self, creds: &Credentials) -> Result<(), c_int> {
+            self.seen.borrow_mut().push(creds.clone());
+            if Some(creds.uid) == self.fail_uid {
+                Err(EPERM)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn creds(uid: u32, gid: u32, groups: &[u32]) -> Credentials {
+        Credentials {
+            uid: uid,
+            gid: gid,
+            groups: groups.to_vec(),
+        }
+    }
+
+    #[test]
+    fn operations_run_as_the_caller() {
+        let recorder = Rc::new(Recorder::default());
+        let daemon = creds(0, 0, &[]);
+        for caller in &[creds(1000, 1000, &[4, 27]), creds(1001, 100, &[])] {
+            let _guard = Guard::new(recorder.clone(), caller, daemon.clone()).unwrap();
+            assert_eq!(recorder.seen.borrow().last(), Some(caller));
+        }
+        assert_eq!(*recorder.seen.borrow(),
+                   vec![creds(1000, 1000, &[4, 27]),
+                        daemon.clone(),
+                        creds(1001, 100, &[]),
+                        daemon.clone()]);
+    }
+
+    #[test]
+    fn failed_switch_restores_immediately() {
+        let recorder = Rc::new(Recorder {
+            fail_uid: Some(1000),
+            ..Recorder::default()
+        });
+        let daemon = creds(0, 0, &[]);
+        let result = Guard::new(recorder.clone(), &creds(1000, 1000, &[]), daemon.clone());
+        assert_eq!(result.err(), Some(EPERM));
+        assert_eq!(recorder.seen.borrow().last(), Some(&daemon));
+    }
+
+    fn attr(kind: FileType, perm: u16, uid: u32, gid: u32) -> FileAttr {
+        FileAttr {
+            perm: perm,
+            uid: uid,
+            gid: gid,
+            ..testutil::attr(2, kind)
+        }
+    }
+
+    #[test]
+    fn access_uses_the_matching_class_only() {
+        // rw- r-- -w-, owned by 1000:100
+        let file = attr(FileType::RegularFile, 0o642, 1000, 100);
+        let owner = creds(1000, 1000, &[]);
+        let member = creds(1001, 1001, &[100]);
+        let other = creds(1002, 1002, &[]);
+
+        assert!(owner.may_access(&file, (R_OK | W_OK) as u32));
+        assert!(!owner.may_access(&file, X_OK as u32));
+        assert!(member.may_access(&file, R_OK as u32));
+        assert!(!member.may_access(&file, W_OK as u32));
+        assert!(other.may_access(&file, W_OK as u32));
+        // Other may write, but the group class doesn't fall through to it
+        assert!(!other.may_access(&file, R_OK as u32));
+        assert!(member.may_access(&file, F_OK as u32));
+    }
+
+    #[test]
+    fn root_needs_an_execute_bit() {
+        let root = creds(0, 0, &[]);
+        let file = attr(FileType::RegularFile, 0o600, 1000, 1000);
+        let script = attr(FileType::RegularFile, 0o701, 1000, 1000);
+        let dir = attr(FileType::Directory, 0o000, 1000, 1000);
+
+        assert!(root.may_access(&file, (R_OK | W_OK) as u32));
+        assert!(!root.may_access(&file, X_OK as u32));
+        assert!(root.may_access(&script, X_OK as u32));
+        assert!(root.may_access(&dir, (R_OK | W_OK | X_OK) as u32));
+    }
+
+    #[test]
+    fn search_needs_x_on_directories() {
+        let dir = attr(FileType::Directory, 0o750, 1000, 100);
+        assert!(creds(1001, 1001, &[100]).may_access(&dir, X_OK as u32));
+        assert!(!creds(1002, 1002, &[]).may_access(&dir, X_OK as u32));
+    }
+
+    #[test]
+    fn requests_run_as_their_caller() {
+        let recorder = Rc::new(Recorder::default());
+        let daemon = creds(0, 0, &[]);
+        let mut callers = Callers::new(recorder.clone(), daemon.clone(), Timespec::new(300, 0));
+        let pid = unsafe { ::libc::getpid() } as u32;
+        let groups = process_groups(pid).unwrap();
+        let now = Timespec::new(1000, 0);
+
+        // What each handler does on the way in, and on the way out once it
+        // has replied
+        for &(uid, gid) in &[(1000, 100), (1001, 1001)] {
+            let caller = callers.credentials(uid, gid, pid, now);
+            let guard = callers.switch_to(&caller).unwrap();
+            assert_eq!(guard.caller(), &creds(uid, gid, &groups));
+            assert_eq!(recorder.seen.borrow().last(), Some(guard.caller()));
+            drop(guard);
+            assert_eq!(recorder.seen.borrow().last(), Some(&daemon));
+        }
+    }
+
+    #[test]
+    fn groups_are_read_again_after_the_timeout() {
+        let mut callers = Callers::new(Rc::new(Recorder::default()),
+                                       creds(0, 0, &[]),
+                                       Timespec::new(300, 0));
+        let pid = unsafe { ::libc::getpid() } as u32;
+        let read_at = Timespec::new(1000, 0);
+        callers.groups.insert(pid, (read_at, vec![4242]));
+        // A process that has gone away since
+        callers.groups.insert(0, (read_at, vec![7]));
+
+        let caller = callers.credentials(1000, 1000, pid, read_at + Duration::seconds(299));
+        assert_eq!(caller.groups, vec![4242]);
+        let caller = callers.credentials(1000, 1000, pid, read_at + Duration::seconds(300));
+        assert_eq!(caller.groups, process_groups(pid).unwrap());
+        assert!(!callers.groups.contains_key(&0));
+    }
+
+    #[test]
+    fn groups_come_from_proc_status() {
+        let status = "Name:\tmake\nUid:\t1000\t1000\t1000\t1000\nGroups:\t4 27 1000 \n\
+                      VmPeak:\t0 kB\n";
+        assert_eq!(groups_from_status(status), vec![4, 27, 1000]);
+        assert_eq!(groups_from_status("Groups:\n"), Vec::<u32>::new());
+        assert_eq!(groups_from_status("Name:\tkthreadd\n"), Vec::<u32>::new());
+    }
+}
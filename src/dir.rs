@@ -0,0 +1,69 @@
+use std::ffi::{CStr, OsStr, OsString};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::ptr;
+
+use gfapi_sys::glfs::{glfs_readdirplus_r, glfs_seekdir, glfs_telldir, Struct_glfs_fd};
+use libc::{c_int, c_long, c_uchar, dirent, stat};
+
+use error::last_errno;
+
+/// A directory entry along with the attributes gluster returned for it.
+pub struct DirEntryPlus {
+    pub name: OsString,
+    pub inode: u64,
+    pub file_type: c_uchar,
+    /// None when the server couldn't supply attributes for this entry
+    pub stat: Option<stat>,
+    /// telldir() cookie that resumes right after this entry
+    pub cookie: u64,
+}
+
+/// Iterates an open directory with readdirplus so every entry comes back with
+/// its stat in the same round trip.
+pub struct DirectoryPlus {
+    dir_handle: *mut Struct_glfs_fd,
+}
+
+impl DirectoryPlus {
+    /// Start reading `dir_handle` at the telldir() cookie `offset`, where 0 is
+    /// the beginning of the directory.
+    pub fn new(dir_handle: *mut Struct_glfs_fd, offset: u64) -> DirectoryPlus {
+        unsafe { glfs_seekdir(dir_handle, offset as c_long) };
+        DirectoryPlus { dir_handle: dir_handle }
+    }
+}
+
+impl Iterator for DirectoryPlus {
+    type Item = Result<DirEntryPlus, c_int>;
+
+    fn next(&mut self) -> Option<Result<DirEntryPlus, c_int>> {
+        let mut entry: dirent = unsafe { mem::zeroed() };
+        let mut entry_stat: stat = unsafe { mem::zeroed() };
+        let mut result: *mut dirent = ptr::null_mut();
+        let ret = unsafe {
+            glfs_readdirplus_r(self.dir_handle, &mut entry_stat, &mut entry, &mut result)
+        };
+        if ret < 0 {
+            return Some(Err(last_errno()));
+        }
+        if result.is_null() {
+            // End of the directory
+            return None;
+        }
+        let name = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
+        let cookie = unsafe { glfs_telldir(self.dir_handle) };
+        Some(Ok(DirEntryPlus {
+            name: OsStr::from_bytes(name.to_bytes()).to_os_string(),
+            inode: entry.d_ino,
+            file_type: entry.d_type,
+            // gluster leaves the stat zeroed when it has nothing to report
+            stat: if entry_stat.st_ino == 0 {
+                None
+            } else {
+                Some(entry_stat)
+            },
+            cookie: cookie as u64,
+        }))
+    }
+}
@@ -0,0 +1,168 @@
+use std::io;
+
+use gfapi_sys::gluster::GlusterError;
+use libc::{c_int, EIO};
+
+/// The errno left behind by the last failed gfapi call.  This has to be read
+/// before anything else (logging included) gets a chance to clobber it.
+pub fn last_errno() -> c_int {
+    errno_from(io::Error::last_os_error().raw_os_error())
+}
+
+/// gfapi reports failures through errno.  A missing or zero errno means the
+/// failure happened somewhere that didn't set one, which is the best fit for
+/// EIO.
+pub fn errno_from(raw: Option<i32>) -> c_int {
+    match raw {
+        Some(errno) if errno > 0 => errno,
+        _ => EIO,
+    }
+}
+
+/// Turns a gfapi result into one carrying the errno to reply with.
+pub trait ToErrno<T> {
+    /// Capture the errno of a failed call and log the failure under `op`.
+    fn errno(self, op: &str) -> Result<T, c_int>;
+}
+
+impl<T> ToErrno<T> for Result<T, GlusterError> {
+    fn errno(self, op: &str) -> Result<T, c_int> {
+        self.map_err(|e| {
+            let errno = last_errno();
+            debug!("{} err: {:?} (errno {})", op, e, errno);
+            errno
+        })
+    }
+}
+
+/// Turns the result of a gfapi call that hands back a new fd into the fd or
+/// the errno to reply with.
+pub trait ToFd<T> {
+    /// Like `errno`, but also catches the null fd gfapi-sys returns as Ok
+    /// when open, opendir or dup fail.
+    fn fd(self, op: &str) -> Result<*mut T, c_int>;
+}
+
+impl<T> ToFd<T> for Result<*mut T, GlusterError> {
+    fn fd(self, op: &str) -> Result<*mut T, c_int> {
+        let fd = self.errno(op)?;
+        if fd.is_null() {
+            let errno = last_errno();
+            debug!("{} err: no fd (errno {})", op, errno);
+            return Err(errno);
+        }
+        Ok(fd)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::ffi::CString;
+    use std::fs::{self, File};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::{Path, PathBuf};
+    use std::process;
+    use std::ptr;
+
+    use gfapi_sys::gluster::GlusterError;
+    use libc::{self, c_int, EACCES, EDQUOT, EEXIST, EIO, EISDIR, ENOENT, ENOSPC, ENOTCONN, ENOTDIR,
+               ENOTEMPTY, EROFS};
+
+    use super::{errno_from, ToErrno, ToFd};
+
+    /// A directory holding one file, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let dir = env::temp_dir().join(format!("glusterfs-fuse-{}-{}", name, process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir(&dir).unwrap();
+            File::create(dir.join("file")).unwrap();
+            Scratch(dir)
+        }
+
+        fn path(&self, name: &str) -> CString {
+            c_path(&self.0.join(name))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn c_path(path: &Path) -> CString {
+        CString::new(path.as_os_str().as_bytes()).unwrap()
+    }
+
+    /// What the gfapi-sys wrappers make of a call's return code
+    fn wrap(ret: c_int) -> Result<(), GlusterError> {
+        if ret < 0 {
+            Err(GlusterError::Error("failed".to_string()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// A gfapi call failing with `errno`, for the failures only a server can
+    /// produce: permissions it enforces, full or quota limited bricks, a read
+    /// only volume or no bricks to talk to at all.
+    fn server_fails(errno: c_int) -> c_int {
+        unsafe { *libc::__errno_location() = errno };
+        -1
+    }
+
+    #[test]
+    fn failed_calls_reply_with_their_errno() {
+        let scratch = Scratch::new("errno");
+        fs::create_dir(scratch.0.join("subdir")).unwrap();
+        let (dir, file) = (c_path(&scratch.0), scratch.path("file"));
+        let (subdir, missing) = (scratch.path("subdir"), scratch.path("gone"));
+
+        // (operation, the failing call, errno the caller should see)
+        let failures: Vec<(&str, Box<dyn Fn() -> c_int>, c_int)> = unsafe {
+            vec![("mkdir", Box::new(|| libc::mkdir(dir.as_ptr(), 0o755)), EEXIST),
+                 ("rmdir", Box::new(|| libc::rmdir(dir.as_ptr())), ENOTEMPTY),
+                 ("rmdir", Box::new(|| libc::rmdir(file.as_ptr())), ENOTDIR),
+                 ("unlink", Box::new(|| libc::unlink(missing.as_ptr())), ENOENT),
+                 ("rename", Box::new(|| libc::rename(file.as_ptr(), subdir.as_ptr())), EISDIR),
+                 ("open", Box::new(|| server_fails(EACCES)), EACCES),
+                 ("write", Box::new(|| server_fails(ENOSPC)), ENOSPC),
+                 ("create", Box::new(|| server_fails(EDQUOT)), EDQUOT),
+                 ("mkdir", Box::new(|| server_fails(EROFS)), EROFS),
+                 ("lookup", Box::new(|| server_fails(ENOTCONN)), ENOTCONN),
+                 ("fsync", Box::new(|| server_fails(0)), EIO)]
+        };
+        for (op, call, errno) in failures {
+            assert_eq!(wrap(call()).errno(op), Err(errno), "{}", op);
+        }
+        assert_eq!(wrap(unsafe { libc::unlink(file.as_ptr()) }).errno("unlink"), Ok(()));
+    }
+
+    #[test]
+    fn null_fd_is_an_error() {
+        let scratch = Scratch::new("fd");
+        unsafe {
+            let missing = Ok(libc::opendir(scratch.path("gone").as_ptr()));
+            assert_eq!(missing.fd("opendir"), Err(ENOENT));
+            let file = Ok(libc::opendir(scratch.path("file").as_ptr()));
+            assert_eq!(file.fd("opendir"), Err(ENOTDIR));
+
+            let dir = Ok(libc::opendir(c_path(&scratch.0).as_ptr())).fd("opendir").unwrap();
+            libc::closedir(dir);
+        }
+    }
+
+    #[test]
+    fn missing_errno_is_eio() {
+        assert_eq!(errno_from(None), EIO);
+        assert_eq!(errno_from(Some(-1)), EIO);
+
+        unsafe { *libc::__errno_location() = 0 };
+        let fd: Result<*mut libc::DIR, GlusterError> = Ok(ptr::null_mut());
+        assert_eq!(fd.fd("open"), Err(EIO));
+    }
+}
@@ -1,26 +1,60 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{OsStr, OsString};
 use std::path::{Path, PathBuf};
-use time;
+use std::rc::Rc;
+use time::{self, Duration, Timespec};
 
 use std::ops::{Index, IndexMut};
 
 use fuse::{FileType, FileAttr};
 use sequence_trie::SequenceTrie;
 
+use volume::Object;
+
+/// The gfid gluster gives the root of every volume
+pub const ROOT_GFID: &'static str = "00000000-0000-0000-0000-000000000001";
+
 #[derive(Debug, Clone)]
 pub struct Inode {
-    pub path: PathBuf,
+    /// Every path this inode is known by.  Hard links give an inode more than
+    /// one, and an unlinked file the kernel still holds has none.
+    pub links: Vec<PathBuf>,
     pub attr: FileAttr, // pub visited: bool,
+    /// When `attr` was fetched from the server
+    pub cached_at: Timespec,
+    /// Lookups the kernel holds on this inode.  It stays cached until the
+    /// kernel forgets all of them.
+    pub lookups: u64,
+    /// Gluster's id for the file, once we've asked for it
+    pub gfid: Option<String>,
+    /// gfapi's handle on the file, once it's been looked up
+    pub object: Option<Rc<Object>>,
 }
 
 impl Inode {
     pub fn new<P: AsRef<Path>>(path: P, attr: FileAttr) -> Inode {
         Inode {
-            path: PathBuf::from(path.as_ref()),
+            links: vec![PathBuf::from(path.as_ref())],
             attr: attr,
+            cached_at: time::get_time(),
+            lookups: 0,
+            gfid: None,
+            object: None,
         }
     }
+
+    /// A path that currently reaches this inode.  Any link will do since they
+    /// all name the same file.
+    pub fn path(&self) -> Option<&Path> {
+        self.links.first().map(|path| path.as_path())
+    }
+
+    /// Whether `attr` is younger than `ttl` and can be used without asking
+    /// the server again.
+    pub fn is_fresh(&self, ttl: Timespec) -> bool {
+        let age = time::get_time() - self.cached_at;
+        age < Duration::seconds(ttl.sec) + Duration::nanoseconds(ttl.nsec as i64)
+    }
 }
 
 #[derive(Debug)]
@@ -28,75 +62,118 @@ pub struct InodeStore {
     inode_map: HashMap<u64, Inode>,
     ino_trie: SequenceTrie<OsString, u64>,
     last_ino: u64,
+    /// Names readdir cached, oldest first, along with when
+    prefetched: VecDeque<(Timespec, PathBuf)>,
 }
 
 fn path_to_sequence(path: &Path) -> Vec<OsString> {
     path.iter().map(|s| s.to_owned()).collect()
 }
 
+fn join_sequence(path: &Path, relative: &[OsString]) -> PathBuf {
+    let mut joined = path.to_path_buf();
+    for component in relative {
+        joined.push(component);
+    }
+    joined
+}
+
 impl InodeStore {
-    pub fn new(perm: u16, uid: u32, gid: u32) -> InodeStore {
+    /// A store holding just the root of the mount.  `fs_root` should carry
+    /// ino 1, which FUSE reserves for the root.
+    pub fn new(fs_root: FileAttr) -> InodeStore {
         let mut store = InodeStore {
             inode_map: HashMap::new(),
             ino_trie: SequenceTrie::new(),
             last_ino: 1, // 1 is reserved for root
-        };
-        let now = time::now_utc().to_timespec();
-        let fs_root = FileAttr {
-            ino: 1,
-            size: 0,
-            blocks: 0,
-            atime: now,
-            mtime: now,
-            ctime: now,
-            crtime: now,
-            kind: FileType::Directory,
-            perm: perm,
-            nlink: 0,
-            uid: uid,
-            gid: gid,
-            rdev: 0,
-            flags: 0,
+            prefetched: VecDeque::new(),
         };
 
-        store.insert(Inode::new("/", fs_root));
+        let mut root = Inode::new("/", fs_root);
+        root.gfid = Some(ROOT_GFID.to_string());
+        store.insert(root);
 
         store
     }
 
+    /// Add or refresh an inode.  Links already known for the ino are kept, so
+    /// seeing a file under a second name adds a hard link instead of
+    /// replacing the first one.  Directories, which can only have one name,
+    /// are moved to the new one instead.
     pub fn insert(&mut self, inode: Inode) {
+        let mut inode = inode;
         let ino = inode.attr.ino;
-        let path = inode.path.clone();
-        let sequence = path_to_sequence(&inode.path);
-
-        if let Some(old_inode) = self.inode_map.insert(ino, inode) {
-            if old_inode.path != path {
-                // VIM seems to break this assumption
-                //panic!("Corrupted inode store: reinserted conflicting ino {} (path={}, \
-                //        oldpath={})",
-                //       ino,
-                //       path.display(),
-                //       old_inode.path.display());
-            } else {
-                println!("Updating ino {} at path {}", ino, path.display());
+        // A directory can't have hard links, so finding one under a new name
+        // means it was renamed behind our back.  Its old name is gone and
+        // whatever is cached below it moved along.
+        if inode.attr.kind == FileType::Directory {
+            if let Some(to) = inode.path().map(|path| path.to_path_buf()) {
+                let old_links = self.get(ino).map(|old| old.links.clone()).unwrap_or_default();
+                for from in old_links.into_iter().filter(|from| *from != to) {
+                    self.rename(&from, &to);
+                }
+            }
+        }
+        let new_links = inode.links.clone();
+        if let Some(old_inode) = self.inode_map.remove(&ino) {
+            // Refreshing the metadata doesn't change what the kernel knows about
+            if inode.lookups == 0 {
+                inode.lookups = old_inode.lookups;
+            }
+            if inode.gfid.is_none() {
+                inode.gfid = old_inode.gfid;
             }
+            if inode.object.is_none() {
+                inode.object = old_inode.object;
+            }
+            let mut links = old_inode.links;
+            for link in inode.links.drain(..) {
+                if !links.contains(&link) {
+                    links.push(link);
+                }
+            }
+            inode.links = links;
+        }
+        self.inode_map.insert(ino, inode);
 
+        for link in new_links {
+            self.set_link(&link, ino);
         }
+    }
 
+    /// Point the trie at `ino` for `path`.  If another inode was cached under
+    /// that name it has been replaced on the server and loses the link.
+    fn set_link(&mut self, path: &Path, ino: u64) {
+        let sequence = path_to_sequence(path);
+        let previous = self.ino_trie.get(&sequence).cloned();
         if !self.ino_trie.insert(&sequence, ino) {
             let mut node = self.ino_trie
                 .get_mut_node(&sequence)
                 .expect(&format!("Corrupt inode store: couldn't insert or modify ino_trie at \
                                   {:?}",
                                  &sequence));
-            // TODO: figure out why this check triggers a false alarm panic on backspacing
-            // to dir and then tabbing
-            // if node.value.is_some() {
-            //     panic!("Corrupt inode store: reinserted ino {} into ino_trie, prev value: {}",
-            // ino, node.value.unwrap());
-            // }
             node.value = Some(ino);
         }
+        if let Some(previous_ino) = previous {
+            if previous_ino != ino {
+                self.detach(previous_ino, path);
+            }
+        }
+    }
+
+    /// Remove `path` from the links of `ino`.  An inode left with no links is
+    /// dropped unless the kernel still holds lookups on it.
+    fn detach(&mut self, ino: u64, path: &Path) {
+        let unreachable = match self.inode_map.get_mut(&ino) {
+            Some(inode) => {
+                inode.links.retain(|link| link != path);
+                inode.links.is_empty() && inode.lookups == 0
+            }
+            None => false,
+        };
+        if unreachable {
+            self.inode_map.remove(&ino);
+        }
     }
 
     pub fn get(&self, ino: u64) -> Option<&Inode> {
@@ -107,6 +184,19 @@ impl InodeStore {
         self.inode_map.get_mut(&ino)
     }
 
+    /// The inode with this gfid, if we've learned it
+    pub fn get_by_gfid(&self, gfid: &str) -> Option<&Inode> {
+        self.inode_map.values().find(|inode| inode.gfid.as_ref().map_or(false, |g| g == gfid))
+    }
+
+    /// Forget how old the cached attributes are so the next getattr or lookup
+    /// asks the server.
+    pub fn invalidate(&mut self, ino: u64) {
+        if let Some(inode) = self.inode_map.get_mut(&ino) {
+            inode.cached_at = Timespec::new(0, 0);
+        }
+    }
+
     pub fn get_by_path<P: AsRef<Path>>(&self, path: P) -> Option<&Inode> {
         let sequence = path_to_sequence(path.as_ref());
         self.ino_trie.get(&sequence).and_then(|ino| self.get(*ino))
@@ -117,31 +207,152 @@ impl InodeStore {
                                            metadata: &FileAttr)
                                            -> Option<&Inode> {
         let ino = metadata.ino.clone();
-        println!("insert metadata: {:?} {}",
-                 metadata,
-                 path.as_ref().display());
+        trace!("insert metadata: {:?} {}",
+               metadata,
+               path.as_ref().display());
 
         self.insert(Inode::new(path, *metadata));
         self.get(ino)
     }
 
+    /// Cache an entry readdir found.  Unless the kernel looks it up it's
+    /// dropped again by `expire_prefetched`.
+    pub fn insert_prefetched<P: AsRef<Path>>(&mut self, path: P, metadata: &FileAttr) {
+        self.insert_metadata(&path, metadata);
+        self.prefetched.push_back((time::get_time(), path.as_ref().to_path_buf()));
+    }
+
+    /// Drop entries readdir cached more than `ttl` ago that the kernel never
+    /// looked up, since they can't answer a lookup anymore.  forget() only
+    /// evicts below directories the kernel lets go of, so without this a
+    /// directory that stays cached, like the root, would keep everything ever
+    /// listed in it.
+    pub fn expire_prefetched(&mut self, ttl: Timespec) {
+        let now = time::get_time();
+        let max_age = Duration::seconds(ttl.sec) + Duration::nanoseconds(ttl.nsec as i64);
+        while let Some(&(cached_at, _)) = self.prefetched.front() {
+            if now - cached_at < max_age {
+                break;
+            }
+            let (_, path) = self.prefetched.pop_front().unwrap();
+            // Listing the directory again may have refreshed the entry
+            let expired = self.get_by_path(&path)
+                .map_or(false, |inode| {
+                    inode.lookups == 0 && inode.attr.ino != 1 && !inode.is_fresh(ttl)
+                });
+            if expired {
+                self.remove_path(&path);
+            }
+        }
+    }
+
     pub fn child<S: AsRef<OsStr>>(&self, ino: u64, name: S) -> Option<&Inode> {
         self.get(ino)
-            .and_then(|inode| {
-                let mut sequence = path_to_sequence(&inode.path);
+            .and_then(|inode| inode.path())
+            .and_then(|path| {
+                let mut sequence = path_to_sequence(path);
                 sequence.push(name.as_ref().to_owned());
                 self.ino_trie.get(&sequence).and_then(|ino| self.get(*ino))
             })
     }
 
-    pub fn remove(&mut self, ino: u64) {
-        let sequence = {
-            let ref path = self.inode_map[&ino].path;
-            path_to_sequence(&path)
+    /// Count an entry reply for `ino` handed to the kernel
+    pub fn lookup(&mut self, ino: u64) {
+        if let Some(inode) = self.inode_map.get_mut(&ino) {
+            inode.lookups += 1;
+        }
+    }
+
+    /// The kernel dropped `nlookup` of its lookups on `ino`.  Once none are
+    /// left the inode is evicted along with any cached descendants the kernel
+    /// never looked up, such as entries readdir prefetched.  The root is never
+    /// evicted.
+    pub fn forget(&mut self, ino: u64, nlookup: u64) {
+        let links = match self.inode_map.get_mut(&ino) {
+            Some(inode) => {
+                inode.lookups = inode.lookups.saturating_sub(nlookup);
+                if inode.lookups > 0 || ino == 1 {
+                    return;
+                }
+                inode.links.clone()
+            }
+            None => return,
         };
 
+        for link in links {
+            for (relative, child_ino) in self.subtree(&link) {
+                let unreferenced = self.inode_map
+                    .get(&child_ino)
+                    .map(|child| child.lookups == 0)
+                    .unwrap_or(true);
+                if !unreferenced {
+                    continue;
+                }
+                let child_path = join_sequence(&link, &relative);
+                self.ino_trie.remove(&path_to_sequence(&child_path));
+                self.detach(child_ino, &child_path);
+            }
+        }
         self.inode_map.remove(&ino);
-        self.ino_trie.remove(&sequence);
+    }
+
+    /// Number of inodes currently cached
+    pub fn len(&self) -> usize {
+        self.inode_map.len()
+    }
+
+    /// Every ino cached at or below `path`, keyed by its path relative to `path`
+    fn subtree(&self, path: &Path) -> Vec<(Vec<OsString>, u64)> {
+        match self.ino_trie.get_node(&path_to_sequence(path)) {
+            Some(node) => {
+                node.iter()
+                    .map(|(key, ino)| (key.into_iter().cloned().collect(), *ino))
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Drop the name `path` and everything cached below it, e.g. after an
+    /// unlink or rmdir or when a rename replaces it.  Other hard links to the
+    /// same inodes stay valid.
+    pub fn remove_path<P: AsRef<Path>>(&mut self, path: P) {
+        let path = path.as_ref();
+        for (relative, ino) in self.subtree(path) {
+            let full_path = join_sequence(path, &relative);
+            self.ino_trie.remove(&path_to_sequence(&full_path));
+            self.detach(ino, &full_path);
+        }
+    }
+
+    /// Re-key `from` and its whole subtree under `to` after a rename.  Anything
+    /// that was cached at `to` was replaced by the rename and is dropped.
+    pub fn rename<P: AsRef<Path>, Q: AsRef<Path>>(&mut self, from: P, to: Q) {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        if from == to {
+            return;
+        }
+        // Renaming one hard link onto another of the same file does nothing
+        let from_ino = self.get_by_path(from).map(|inode| inode.attr.ino);
+        if from_ino.is_some() && from_ino == self.get_by_path(to).map(|inode| inode.attr.ino) {
+            return;
+        }
+        let moved = self.subtree(from);
+        self.remove_path(to);
+
+        for (relative, ino) in moved {
+            let old_path = join_sequence(from, &relative);
+            let new_path = join_sequence(to, &relative);
+            self.ino_trie.remove(&path_to_sequence(&old_path));
+            if let Some(inode) = self.inode_map.get_mut(&ino) {
+                for link in inode.links.iter_mut() {
+                    if *link == old_path {
+                        *link = new_path.clone();
+                    }
+                }
+            }
+            self.set_link(&new_path, ino);
+        }
     }
 }
 
@@ -158,3 +369,173 @@ impl IndexMut<u64> for InodeStore {
         self.get_mut(index).unwrap()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use fuse::FileType;
+    use time::Timespec;
+
+    use super::{Inode, InodeStore};
+    use testutil::attr;
+
+    fn store() -> InodeStore {
+        let mut store = InodeStore::new(attr(1, FileType::Directory));
+        store.insert(Inode::new("/a", attr(2, FileType::Directory)));
+        store.insert(Inode::new("/a/b", attr(3, FileType::Directory)));
+        store.insert(Inode::new("/a/b/c", attr(4, FileType::RegularFile)));
+        store.insert(Inode::new("/d", attr(5, FileType::RegularFile)));
+        store
+    }
+
+    #[test]
+    fn rename_moves_subtree() {
+        let mut store = store();
+        store.rename("/a", "/z");
+
+        assert!(store.get_by_path("/a").is_none());
+        assert!(store.get_by_path("/a/b/c").is_none());
+        assert_eq!(store[2].path(), Some(Path::new("/z")));
+        assert_eq!(store[4].path(), Some(Path::new("/z/b/c")));
+        assert_eq!(store.get_by_path("/z/b").map(|i| i.attr.ino), Some(3));
+        assert_eq!(store.child(3, "c").map(|i| i.attr.ino), Some(4));
+    }
+
+    #[test]
+    fn rename_over_existing_target() {
+        let mut store = store();
+        store.rename("/d", "/a/b/c");
+
+        assert!(store.get(4).is_none());
+        assert!(store.get_by_path("/d").is_none());
+        assert_eq!(store.get_by_path("/a/b/c").map(|i| i.attr.ino), Some(5));
+    }
+
+    #[test]
+    fn forget_evicts_at_zero() {
+        let mut store = store();
+        store.lookup(5);
+        store.lookup(5);
+        store.forget(5, 1);
+        assert!(store.get(5).is_some());
+
+        // Refreshing the attributes keeps the kernel's lookups
+        store.insert(Inode::new("/d", attr(5, FileType::RegularFile)));
+        assert_eq!(store[5].lookups, 1);
+
+        store.forget(5, 1);
+        assert!(store.get(5).is_none());
+        assert!(store.get_by_path("/d").is_none());
+    }
+
+    #[test]
+    fn forget_drops_unreferenced_descendants() {
+        let mut store = store();
+        store.lookup(2);
+        store.lookup(3);
+        store.forget(2, 1);
+
+        // /a/b/c was never looked up, /a/b still is
+        assert!(store.get(2).is_none());
+        assert_eq!(store.get(3).map(|i| i.lookups), Some(1));
+        assert!(store.get(4).is_none());
+        assert_eq!(store.len(), 3);
+    }
+
+    #[test]
+    fn prefetched_entries_expire_unless_looked_up() {
+        let mut store = store();
+        store.insert_prefetched("/x", &attr(6, FileType::RegularFile));
+        store.insert_prefetched("/a/y", &attr(7, FileType::RegularFile));
+        store.lookup(7);
+
+        store.expire_prefetched(Timespec::new(60, 0));
+        assert!(store.get(6).is_some());
+
+        store.expire_prefetched(Timespec::new(0, 0));
+        assert!(store.get(6).is_none());
+        assert!(store.get_by_path("/x").is_none());
+        assert_eq!(store.get_by_path("/a/y").map(|i| i.lookups), Some(1));
+        assert_eq!(store.len(), 6);
+    }
+
+    #[test]
+    fn refresh_keeps_gfid() {
+        let mut store = store();
+        store.get_mut(5).unwrap().gfid = Some("f4b3".to_string());
+        store.insert(Inode::new("/d", attr(5, FileType::RegularFile)));
+        assert_eq!(store[5].gfid, Some("f4b3".to_string()));
+
+        // and survives a rename done through this mount
+        store.rename("/d", "/e");
+        assert_eq!(store.get_by_path("/e").and_then(|i| i.gfid.clone()),
+                   Some("f4b3".to_string()));
+    }
+
+    #[test]
+    fn forget_never_evicts_root() {
+        let mut store = store();
+        store.forget(1, 10);
+        assert!(store.get(1).is_some());
+    }
+
+    #[test]
+    fn hard_links_share_an_inode() {
+        let mut store = store();
+        store.insert(Inode::new("/a/e", attr(5, FileType::RegularFile)));
+        assert_eq!(store[5].links.len(), 2);
+        assert_eq!(store.get_by_path("/a/e").map(|i| i.attr.ino), Some(5));
+
+        // Dropping one name leaves the other working
+        store.remove_path("/d");
+        assert!(store.get_by_path("/d").is_none());
+        assert_eq!(store[5].path(), Some(Path::new("/a/e")));
+        assert_eq!(store.child(2, "e").map(|i| i.attr.ino), Some(5));
+
+        store.remove_path("/a/e");
+        assert!(store.get(5).is_none());
+    }
+
+    #[test]
+    fn unlinked_inode_kept_while_referenced() {
+        let mut store = store();
+        store.lookup(5);
+        store.remove_path("/d");
+        assert_eq!(store.get(5).map(|i| i.path()), Some(None));
+
+        store.forget(5, 1);
+        assert!(store.get(5).is_none());
+    }
+
+    #[test]
+    fn rename_between_links_of_same_file() {
+        let mut store = store();
+        store.insert(Inode::new("/a/e", attr(5, FileType::RegularFile)));
+        store.rename("/d", "/a/e");
+        assert_eq!(store[5].links.len(), 2);
+        assert!(store.get_by_path("/d").is_some());
+    }
+
+    #[test]
+    fn directory_under_new_name_replaces_old() {
+        let mut store = store();
+        store.insert(Inode::new("/z", attr(3, FileType::Directory)));
+
+        assert_eq!(store[3].links, vec![Path::new("/z")]);
+        assert!(store.get_by_path("/a/b").is_none());
+        assert_eq!(store.get_by_path("/z/c").map(|i| i.attr.ino), Some(4));
+        assert_eq!(store[4].path(), Some(Path::new("/z/c")));
+    }
+
+    #[test]
+    fn remove_path_drops_subtree() {
+        let mut store = store();
+        store.remove_path("/a/b");
+
+        assert!(store.get(3).is_none());
+        assert!(store.get(4).is_none());
+        assert!(store.get_by_path("/a/b/c").is_none());
+        assert_eq!(store.get_by_path("/a").map(|i| i.attr.ino), Some(2));
+    }
+}
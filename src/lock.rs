@@ -0,0 +1,304 @@
+use std::collections::HashSet;
+use std::i64;
+use std::mem;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use fuse::ReplyEmpty;
+use gfapi_sys::glfs::{glfs_posix_lock, Struct_glfs_fd};
+use libc::{c_int, c_short, c_void, flock, off_t, pid_t, EAGAIN, EACCES, EBADF, EINTR, F_GETLK,
+           F_SETLK, SEEK_SET};
+
+use creds::{CredentialSwitch, Credentials, Gfapi, Guard};
+use error::last_errno;
+
+/// The kernel's OFFSET_MAX, used as the end of a lock that runs to EOF.
+pub const OFFSET_MAX: u64 = i64::MAX as u64;
+
+/// fcntl's l_type for unlocking, which the libc crate doesn't have for Linux
+pub const F_UNLCK: c_int = 2;
+
+// How long a blocking setlk waits before retrying a conflicting lock
+const RETRY_INTERVAL_MS: u64 = 100;
+
+// How often a blocking setlk is retried before it gives up with EINTR, which
+// callers of fcntl(F_SETLKW) already have to be ready for.  About 10 minutes.
+const MAX_RETRIES: u32 = 6000;
+
+// gfapi-sys has glfs_posix_lock, but Gluster::posixlock passes flock(2)'s
+// LOCK_* values as the fcntl command, so the raw call is used instead.
+#[link(name = "gfapi")]
+extern "C" {
+    // Not in gfapi-sys
+    fn glfs_fd_set_lkowner(fd: *mut Struct_glfs_fd, data: *mut c_void, len: c_int) -> c_int;
+}
+
+/// A byte range lock as FUSE describes it.  `end` is inclusive.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Lock {
+    pub start: u64,
+    pub end: u64,
+    pub typ: u32,
+    pub pid: u32,
+}
+
+impl Lock {
+    pub fn new(start: u64, end: u64, typ: u32, pid: u32) -> Lock {
+        Lock {
+            start: start,
+            end: end,
+            typ: typ,
+            pid: pid,
+        }
+    }
+
+    /// A lock covering the whole file
+    pub fn whole_file(typ: u32, pid: u32) -> Lock {
+        Lock::new(0, OFFSET_MAX, typ, pid)
+    }
+
+    fn to_flock(&self) -> flock {
+        let mut fl: flock = unsafe { mem::zeroed() };
+        fl.l_type = self.typ as c_short;
+        fl.l_whence = SEEK_SET as c_short;
+        fl.l_start = self.start as off_t;
+        // A length of 0 means "through the end of the file"
+        fl.l_len = if self.end >= OFFSET_MAX {
+            0
+        } else {
+            (self.end - self.start + 1) as off_t
+        };
+        fl.l_pid = self.pid as pid_t;
+        fl
+    }
+
+    fn from_flock(fl: &flock) -> Lock {
+        let start = fl.l_start as u64;
+        let end = if fl.l_len == 0 {
+            OFFSET_MAX
+        } else {
+            start + fl.l_len as u64 - 1
+        };
+        Lock::new(start, end, fl.l_type as u32, fl.l_pid as u32)
+    }
+}
+
+/// Tag the fd so the posix locks translator attributes the next lock call to
+/// `owner`.  Lock ownership is per lk-owner, not per fd, on the server.
+pub fn set_owner(fd: *mut Struct_glfs_fd, owner: u64) -> Result<(), c_int> {
+    let mut owner = owner;
+    let ret = unsafe {
+        glfs_fd_set_lkowner(fd,
+                            &mut owner as *mut u64 as *mut c_void,
+                            mem::size_of::<u64>() as c_int)
+    };
+    if ret < 0 { Err(last_errno()) } else { Ok(()) }
+}
+
+fn posix_lock(fd: *mut Struct_glfs_fd, cmd: c_int, fl: &mut flock) -> Result<(), c_int> {
+    let ret = unsafe { glfs_posix_lock(fd, cmd, fl) };
+    if ret < 0 { Err(last_errno()) } else { Ok(()) }
+}
+
+/// A blocking setlk waiting for a conflicting lock to go away
+#[derive(Debug)]
+struct Waiter {
+    fh: u64,
+    owner: u64,
+    lock: Lock,
+    /// Who asked for the lock.  Retries happen outside of the request.
+    caller: Credentials,
+    retries: u32,
+    reply: ReplyEmpty,
+}
+
+#[derive(Debug, Default)]
+struct WaitQueue {
+    waiters: Vec<Waiter>,
+    /// Whether the thread retrying `waiters` is running
+    retrying: bool,
+}
+
+/// Serializes lock calls against open gluster fds.
+///
+/// The lk-owner is state on the fd, so setting it and issuing the lock has
+/// to happen without another lock call on the same fd sneaking in between.
+/// Blocking locks are queued and retried from a helper thread so a waiter
+/// can't stall the whole FUSE session, which would also stop the holder from
+/// unlocking.
+#[derive(Clone, Debug)]
+pub struct LockManager {
+    // fds that are still open.  A waiter gives up once its fd is released.
+    open: Arc<Mutex<HashSet<u64>>>,
+    queue: Arc<Mutex<WaitQueue>>,
+    /// What the retry thread runs as between retries
+    daemon: Credentials,
+}
+
+impl LockManager {
+    pub fn new(daemon: Credentials) -> LockManager {
+        LockManager {
+            open: Arc::new(Mutex::new(HashSet::new())),
+            queue: Arc::new(Mutex::new(WaitQueue::default())),
+            daemon: daemon,
+        }
+    }
+
+    /// Track a newly opened file handle
+    pub fn opened(&self, fh: u64) {
+        self.open.lock().unwrap().insert(fh);
+    }
+
+    /// Drop every lock `owner` holds through this handle and stop tracking it.
+    /// Must be called before the fd is closed.  Waiters queued on the handle
+    /// get EBADF now, since the address may be handed out again for an fd
+    /// they never opened.
+    pub fn released(&self, fh: u64, owner: u64) -> Result<(), c_int> {
+        let mut open = self.open.lock().unwrap();
+        if !open.remove(&fh) {
+            return Ok(());
+        }
+        {
+            let mut queue = self.queue.lock().unwrap();
+            let waiters = mem::replace(&mut queue.waiters, Vec::new());
+            let (closed, waiting): (Vec<Waiter>, Vec<Waiter>) =
+                waiters.into_iter().partition(|waiter| waiter.fh == fh);
+            queue.waiters = waiting;
+            for waiter in closed {
+                waiter.reply.error(EBADF);
+            }
+        }
+        let fd = fh as *mut Struct_glfs_fd;
+        set_owner(fd, owner)?;
+        let mut fl = Lock::whole_file(F_UNLCK as u32, 0).to_flock();
+        posix_lock(fd, F_SETLK, &mut fl)
+    }
+
+    /// Test for a conflicting lock.  Returns the conflicting lock, or one with
+    /// typ F_UNLCK if `lock` could be placed.
+    pub fn getlk(&self, fh: u64, owner: u64, lock: &Lock) -> Result<Lock, c_int> {
+        let open = self.open.lock().unwrap();
+        if !open.contains(&fh) {
+            return Err(EBADF);
+        }
+        let fd = fh as *mut Struct_glfs_fd;
+        set_owner(fd, owner)?;
+        let mut fl = lock.to_flock();
+        posix_lock(fd, F_GETLK, &mut fl)?;
+        Ok(Lock::from_flock(&fl))
+    }
+
+    /// Place, change or drop (F_UNLCK) a lock without waiting.  A conflict
+    /// comes back as EAGAIN.
+    pub fn setlk(&self, fh: u64, owner: u64, lock: &Lock) -> Result<(), c_int> {
+        let open = self.open.lock().unwrap();
+        if !open.contains(&fh) {
+            return Err(EBADF);
+        }
+        let fd = fh as *mut Struct_glfs_fd;
+        set_owner(fd, owner)?;
+        let mut fl = lock.to_flock();
+        posix_lock(fd, F_SETLK, &mut fl).map_err(|errno| if errno == EACCES {
+            EAGAIN
+        } else {
+            errno
+        })
+    }
+
+    /// Place a lock, waiting for conflicting locks to go away.  The reply is
+    /// sent from the retry thread once the lock is granted.
+    pub fn setlk_wait(&self,
+                      fh: u64,
+                      owner: u64,
+                      lock: Lock,
+                      caller: Credentials,
+                      reply: ReplyEmpty) {
+        match self.setlk(fh, owner, &lock) {
+            Ok(_) => return reply.ok(),
+            Err(EAGAIN) => {}
+            Err(errno) => return reply.error(errno),
+        }
+        let mut queue = self.queue.lock().unwrap();
+        queue.waiters.push(Waiter {
+            fh: fh,
+            owner: owner,
+            lock: lock,
+            caller: caller,
+            retries: 0,
+            reply: reply,
+        });
+        if !queue.retrying {
+            queue.retrying = true;
+            let locks = self.clone();
+            thread::spawn(move || locks.retry_waiters());
+        }
+    }
+
+    /// Retry the queued locks until none are left waiting.  gfapi credentials
+    /// are per thread, so each retry is made as the caller who's waiting.
+    fn retry_waiters(&self) {
+        let switch: Rc<dyn CredentialSwitch> = Rc::new(Gfapi);
+        loop {
+            thread::sleep(Duration::from_millis(RETRY_INTERVAL_MS));
+            let waiters = {
+                let mut queue = self.queue.lock().unwrap();
+                if queue.waiters.is_empty() {
+                    queue.retrying = false;
+                    return;
+                }
+                mem::replace(&mut queue.waiters, Vec::new())
+            };
+
+            let mut still_waiting = Vec::new();
+            for mut waiter in waiters {
+                let result = Guard::new(switch.clone(), &waiter.caller, self.daemon.clone())
+                    .and_then(|_caller| self.setlk(waiter.fh, waiter.owner, &waiter.lock));
+                match result {
+                    Ok(_) => waiter.reply.ok(),
+                    Err(EAGAIN) if waiter.retries < MAX_RETRIES => {
+                        waiter.retries += 1;
+                        still_waiting.push(waiter);
+                    }
+                    Err(EAGAIN) => waiter.reply.error(EINTR),
+                    Err(errno) => waiter.reply.error(errno),
+                }
+            }
+            self.queue.lock().unwrap().waiters.extend(still_waiting);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use libc::SEEK_SET;
+
+    use super::{Lock, F_UNLCK, OFFSET_MAX};
+
+    // fcntl's l_type for a write lock
+    const F_WRLCK: u32 = 1;
+
+    #[test]
+    fn range_becomes_start_and_length() {
+        let lock = Lock::new(10, 19, F_WRLCK, 42);
+        let fl = lock.to_flock();
+        assert_eq!((fl.l_type as u32, fl.l_whence as i32), (F_WRLCK, SEEK_SET));
+        assert_eq!((fl.l_start, fl.l_len, fl.l_pid), (10, 10, 42));
+        assert_eq!(Lock::from_flock(&fl), lock);
+
+        // A single byte
+        assert_eq!(Lock::new(5, 5, F_WRLCK, 42).to_flock().l_len, 1);
+    }
+
+    #[test]
+    fn lock_to_eof_has_length_zero() {
+        let lock = Lock::new(100, OFFSET_MAX, F_WRLCK, 7);
+        let fl = lock.to_flock();
+        assert_eq!((fl.l_start, fl.l_len), (100, 0));
+        assert_eq!(Lock::from_flock(&fl), lock);
+
+        let whole = Lock::whole_file(F_UNLCK as u32, 0);
+        assert_eq!(Lock::from_flock(&whole.to_flock()), whole);
+    }
+}
@@ -8,24 +8,47 @@ extern crate log;
 extern crate sequence_trie;
 extern crate time;
 
-use std::ffi::OsStr;
-use std::path::Path;
+use std::ffi::{OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 
 use clap::{Arg, App};
 use fuse::{FileAttr, Filesystem, FileType, Request, ReplyAttr, ReplyDirectory, ReplyEmpty,
            ReplyEntry, ReplyOpen, ReplyStatfs, ReplyWrite, ReplyData, ReplyXattr, ReplyCreate,
            ReplyLock};
-use gfapi_sys::gluster::{Gluster, GlusterDirectory};
+use gfapi_sys::gluster::Gluster;
 use gfapi_sys::glfs::Struct_glfs_fd;
-use libc::{c_uchar, DT_REG, DT_DIR, DT_FIFO, DT_CHR, DT_BLK, DT_LNK, EIO, ENODATA, ENOENT, ENOSYS,
-           ERANGE, S_IFMT, S_IFREG, S_IFDIR, S_IFCHR, S_IFBLK, S_IFIFO, S_IFLNK, timespec};
+use libc::{c_int, c_long, c_uchar, DT_REG, DT_DIR, DT_FIFO, DT_CHR, DT_BLK, DT_LNK, EACCES,
+           EINVAL, EIO, ENOENT, ERANGE, EROFS, O_ACCMODE, O_RDONLY, O_TRUNC, PATH_MAX, S_IFMT,
+           S_IFREG, S_IFDIR, S_IFCHR, S_IFBLK, S_IFIFO, S_IFLNK, W_OK, timespec};
 use time::Timespec;
 
+mod creds;
+mod dir;
+mod error;
 mod inode;
+mod lock;
+#[cfg(test)]
+mod testutil;
+mod upcall;
+mod volume;
+use creds::{Callers, Credentials, Gfapi, Guard};
+use dir::DirectoryPlus;
+use error::{ToErrno, ToFd};
 use inode::InodeStore;
+use lock::{Lock, LockManager};
+use upcall::Upcalls;
+use volume::{Object, Volume};
+
+// Default attribute and entry timeout, the same as the C client
+const DEFAULT_TIMEOUT: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
+// Default gid-timeout, the same as the C client
+const DEFAULT_GID_TIMEOUT: Timespec = Timespec { sec: 300, nsec: 0 };
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 }; // 1 second
+// utimensat()'s "leave this time alone", which the libc crate doesn't have
+const UTIME_OMIT: c_long = (1 << 30) - 2;
 
 fn filetype_from_uchar(f_type: c_uchar) -> Option<FileType> {
     match f_type {
@@ -52,34 +75,551 @@ fn filetype_from_mode(mode_t: u32) -> Option<FileType> {
     }
 }
 
+fn attr_from_stat(stat: &libc::stat) -> Option<FileAttr> {
+    let device_type = match filetype_from_mode(stat.st_mode) {
+        Some(kind) => kind,
+        None => return None,
+    };
+    Some(FileAttr {
+        ino: stat.st_ino,
+        size: stat.st_size as u64,
+        blocks: stat.st_blocks as u64,
+        atime: Timespec {
+            sec: stat.st_atime,
+            nsec: stat.st_atime_nsec as i32,
+        },
+        mtime: Timespec {
+            sec: stat.st_mtime,
+            nsec: stat.st_mtime_nsec as i32,
+        },
+        ctime: Timespec {
+            sec: stat.st_ctime,
+            nsec: stat.st_ctime_nsec as i32,
+        },
+        // struct stat has no birth time and gfapi offers no statx
+        crtime: Timespec::new(0, 0),
+        kind: device_type,
+        // Permission bits plus setuid, setgid and sticky
+        perm: (stat.st_mode & 0o7777) as u16,
+        nlink: stat.st_nlink as u32,
+        uid: stat.st_uid,
+        gid: stat.st_gid,
+        rdev: rdev_to_fuse(stat.st_rdev as u64),
+        flags: 0,
+    })
+}
+
+/// Pack a glibc dev_t into the 32 bit new_encode_dev() layout FUSE uses:
+/// 12 bits of major and 20 bits of minor.
+fn rdev_to_fuse(rdev: u64) -> u32 {
+    let major = ((rdev >> 8) & 0xfff) | ((rdev >> 32) & !0xfff);
+    let minor = (rdev & 0xff) | ((rdev >> 12) & !0xff);
+    ((minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+/// Unpack the rdev FUSE hands to mknod into a glibc dev_t.
+fn rdev_from_fuse(rdev: u32) -> u64 {
+    let major = ((rdev & 0xfff00) >> 8) as u64;
+    let minor = ((rdev & 0xff) | ((rdev >> 12) & 0xfff00)) as u64;
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12) | ((major & !0xfff) << 32)
+}
+
+/// The target glfs_readlink left in `buf`.  It doesn't nul terminate the
+/// target, so `buf` starts out zeroed, and since a link target can't contain
+/// a nul the first one marks the end.
+fn link_target(buf: &[u8]) -> &[u8] {
+    let len = buf.iter().position(|b| *b == 0).unwrap_or(buf.len());
+    &buf[..len]
+}
+
+/// Convert a setattr time into what utimensat expects.  Times that weren't
+/// asked for become UTIME_OMIT so they're left alone.  A "set to now" request
+/// already arrives carrying the kernel's current time.
+fn utime_spec(time: Option<Timespec>) -> timespec {
+    match time {
+        Some(t) => {
+            timespec {
+                tv_sec: t.sec,
+                tv_nsec: t.nsec as i64,
+            }
+        }
+        None => {
+            timespec {
+                tv_sec: 0,
+                tv_nsec: UTIME_OMIT,
+            }
+        }
+    }
+}
+
+/// attr_from_stat for the stat of `path`, failing with EIO for file types
+/// FUSE can't express.
+fn file_attr(path: &Path, stat: &libc::stat) -> Result<FileAttr, c_int> {
+    attr_from_stat(stat).ok_or_else(|| {
+        error!("Unable to determine file type of {}: {}",
+               path.display(),
+               stat.st_mode);
+        EIO
+    })
+}
+
+/// The mount root as the kernel should see it.  FUSE wants it as ino 1
+/// whatever gluster numbers it, and the mount options may override its mode
+/// and owner.
+fn root_attr(attr: FileAttr, mode: Option<u16>, uid: Option<u32>, gid: Option<u32>) -> FileAttr {
+    FileAttr {
+        ino: 1,
+        perm: mode.unwrap_or(attr.perm),
+        uid: uid.unwrap_or(attr.uid),
+        gid: gid.unwrap_or(attr.gid),
+        ..attr
+    }
+}
+
+/// Parse a permission mode given in octal
+fn parse_mode(value: &str) -> Result<u16, String> {
+    match u16::from_str_radix(value, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("Error: {} is not a valid octal mode", value)),
+    }
+}
+
+/// Whether open() flags ask for write access or would truncate the file
+fn opens_for_writing(flags: u32) -> bool {
+    let flags = flags as c_int;
+    flags & O_ACCMODE != O_RDONLY || flags & O_TRUNC != 0
+}
+
+/// Path that reaches a file by its gfid no matter what it's called.  Gluster
+/// serves this from the virtual /.gfid directory at the volume root.
+fn gfid_path(gfid: &str) -> PathBuf {
+    Path::new("/.gfid").join(gfid)
+}
+
+/// The path that reaches `ino` without looking anything up, if there is one:
+/// the root, or the gfid path of a file whose gfid we know.
+fn gfid_path_of(inodes: &InodeStore, ino: u64) -> Option<PathBuf> {
+    if ino == 1 {
+        return Some(PathBuf::from("/"));
+    }
+    inodes.get(ino).and_then(|inode| inode.gfid.as_ref()).map(|gfid| gfid_path(gfid))
+}
+
+/// Parse a timeout given in seconds, fractions allowed, as the C client's
+/// attribute-timeout and friends are.
+fn parse_timeout(value: &str) -> Result<Timespec, String> {
+    match f64::from_str(value) {
+        Ok(secs) if secs >= 0.0 && secs.is_finite() => {
+            Ok(Timespec::new(secs.trunc() as i64,
+                             (secs.fract() * 1_000_000_000.0).round() as i32))
+        }
+        _ => Err(format!("Error: {} is not a valid number of seconds", value)),
+    }
+}
+
+/// The attributes of a negative entry.  An entry reply with ino 0 tells the
+/// kernel the name doesn't exist and lets it cache that for the entry timeout.
+fn negative_entry() -> FileAttr {
+    let epoch = Timespec::new(0, 0);
+    FileAttr {
+        ino: 0,
+        size: 0,
+        blocks: 0,
+        atime: epoch,
+        mtime: epoch,
+        ctime: epoch,
+        crtime: epoch,
+        kind: FileType::RegularFile,
+        perm: 0,
+        nlink: 0,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
+
+/// Answer a getxattr/listxattr request.  A size of 0 is the caller probing for
+/// how big a buffer it needs, anything else has to fit the whole value.
+fn reply_xattr(reply: ReplyXattr, data: &[u8], size: u32) {
+    if size == 0 {
+        reply.size(data.len() as u32);
+    } else if data.len() as u32 > size {
+        reply.error(ERANGE);
+    } else {
+        reply.data(data);
+    }
+}
+
+/// The gluster calls behind opendir and releasedir
+trait DirHandles {
+    fn opendir(&self, path: &Path) -> Result<u64, c_int>;
+    fn closedir(&self, fh: u64) -> Result<(), c_int>;
+}
+
+impl DirHandles for Gluster {
+    fn opendir(&self, path: &Path) -> Result<u64, c_int> {
+        Gluster::opendir(self, path).fd("opendir").map(|fd| fd as u64)
+    }
+
+    fn closedir(&self, fh: u64) -> Result<(), c_int> {
+        self.close(fh as *mut Struct_glfs_fd).errno("releasedir")
+    }
+}
+
+/// Tally of gluster handles opened and closed, used to spot handle leaks.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct HandleCounter {
+    pub opened: u64,
+    pub closed: u64,
+}
+
+impl HandleCounter {
+    /// Open a directory handle, counting it unless the open failed
+    fn opendir<D: DirHandles>(&mut self, dirs: &D, path: &Path) -> Result<u64, c_int> {
+        let fh = dirs.opendir(path)?;
+        self.opened += 1;
+        Ok(fh)
+    }
+
+    /// Close a directory handle.  glfs_close frees the handle even when it
+    /// reports an error, so failures count too.
+    fn releasedir<D: DirHandles>(&mut self, dirs: &D, fh: u64) -> Result<(), c_int> {
+        let result = dirs.closedir(fh);
+        self.closed += 1;
+        trace!("releasedir: {} directory handles outstanding", self.outstanding());
+        result
+    }
+
+    /// Handles that were opened and haven't been closed yet
+    pub fn outstanding(&self) -> u64 {
+        self.opened.saturating_sub(self.closed)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use std::collections::HashMap;
-    use super::GlusterFilesystem;
-    use std::path::PathBuf;
+    use std::cell::{Cell, RefCell};
+    use std::collections::HashSet;
+    use std::env;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::fs;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::symlink;
+    use std::path::{Path, PathBuf};
+    use std::process;
+
+    use fuse::{FileAttr, FileType};
+    use libc::{self, c_char, c_int, EACCES, EIO, O_APPEND, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
+               PATH_MAX};
+    use time::Timespec;
+
+    use inode::{Inode, InodeStore};
+    use super::{gfid_path_of, link_target, opens_for_writing, parse_mode, parse_timeout,
+                rdev_from_fuse, rdev_to_fuse, root_attr, DirHandles, HandleCounter, MountOptions};
+    use testutil;
+
+    #[test]
+    fn link_targets_are_raw_bytes() {
+        // Not valid UTF-8, which has to come back untouched
+        let target: &[u8] = b"caf\xe9/\xff\xfe";
+        let link = env::temp_dir().join(format!("glusterfs-fuse-link-{}", process::id()));
+        let _ = fs::remove_file(&link);
+        symlink(OsStr::from_bytes(target), &link).unwrap();
+
+        // The same zeroed PATH_MAX buffer readlink hands to glfs_readlink
+        let mut buf: Vec<u8> = vec![0; PATH_MAX as usize];
+        let c_link = CString::new(link.as_os_str().as_bytes()).unwrap();
+        let len = unsafe {
+            libc::readlink(c_link.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.len())
+        };
+        fs::remove_file(&link).unwrap();
+        assert_eq!(len, target.len() as isize);
+        assert_eq!(link_target(&buf), target);
+
+        // A target that fills the whole buffer has no nul to stop at
+        assert_eq!(link_target(b"abc"), b"abc");
+    }
+
+    #[test]
+    fn files_resolve_through_their_gfid() {
+        let gfid = "8a3f1c02-d45e-4b7a-9c10-ee0123456789";
+        let mut inodes = InodeStore::new(testutil::attr(1, FileType::Directory));
+        let mut file = Inode::new("/dir/file", testutil::attr(5, FileType::RegularFile));
+        file.gfid = Some(gfid.to_string());
+        inodes.insert(file);
+        // Listed by readdir, which doesn't tell us the gfid
+        inodes.insert(Inode::new("/dir/listed", testutil::attr(6, FileType::RegularFile)));
+
+        let by_gfid = PathBuf::from("/.gfid/8a3f1c02-d45e-4b7a-9c10-ee0123456789");
+        assert_eq!(gfid_path_of(&inodes, 1), Some(PathBuf::from("/")));
+        assert_eq!(gfid_path_of(&inodes, 5), Some(by_gfid.clone()));
+        // A rename doesn't change how the file is reached
+        inodes.rename("/dir/file", "/elsewhere");
+        assert_eq!(gfid_path_of(&inodes, 5), Some(by_gfid));
+        // These have to be looked up first
+        assert_eq!(gfid_path_of(&inodes, 6), None);
+        assert_eq!(gfid_path_of(&inodes, 7), None);
+    }
+
+    #[test]
+    fn rdev_round_trip() {
+        // /dev/null, /dev/sda and a device with a minor above 255
+        for &(major, minor) in &[(1u64, 3u64), (8, 0), (259, 65536)] {
+            let dev = (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12);
+            let packed = rdev_to_fuse(dev);
+            assert_eq!(packed as u64 & 0xff, minor & 0xff);
+            assert_eq!(rdev_from_fuse(packed), dev);
+        }
+    }
+
+    /// Stands in for gluster's directory handles
+    #[derive(Default)]
+    struct FakeVolume {
+        open: RefCell<HashSet<u64>>,
+        next: Cell<u64>,
+    }
+
+    impl DirHandles for FakeVolume {
+        fn opendir(&self, path: &Path) -> Result<u64, c_int> {
+            if path.ends_with("private") {
+                return Err(EACCES);
+            }
+            self.next.set(self.next.get() + 1);
+            self.open.borrow_mut().insert(self.next.get());
+            Ok(self.next.get())
+        }
+
+        fn closedir(&self, fh: u64) -> Result<(), c_int> {
+            // The handle is gone whether or not the close succeeds
+            if self.open.borrow_mut().remove(&fh) && fh % 2 == 0 {
+                Err(EIO)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    #[test]
+    fn timeouts_parse_as_seconds() {
+        assert_eq!(parse_timeout("0"), Ok(Timespec::new(0, 0)));
+        assert_eq!(parse_timeout("30"), Ok(Timespec::new(30, 0)));
+        assert_eq!(parse_timeout("1.5"), Ok(Timespec::new(1, 500_000_000)));
+        assert!(parse_timeout("-1").is_err());
+        assert!(parse_timeout("soon").is_err());
+    }
+
+    #[test]
+    fn root_overrides_apply_field_by_field() {
+        let volume_root = FileAttr {
+            perm: 0o700,
+            uid: 1000,
+            gid: 1000,
+            ..testutil::attr(1, FileType::Directory)
+        };
+
+        let root = root_attr(volume_root, None, None, None);
+        assert_eq!((root.ino, root.perm, root.uid, root.gid), (1, 0o700, 1000, 1000));
+        let root = root_attr(volume_root, Some(0o1777), None, Some(100));
+        assert_eq!((root.ino, root.perm, root.uid, root.gid), (1, 0o1777, 1000, 100));
+    }
+
+    #[test]
+    fn modes_parse_as_octal() {
+        assert_eq!(parse_mode("755"), Ok(0o755));
+        assert_eq!(parse_mode("1777"), Ok(0o1777));
+        assert!(parse_mode("17777").is_err());
+        assert!(parse_mode("rwx").is_err());
+    }
+
+    #[test]
+    fn write_opens_are_recognized() {
+        assert!(!opens_for_writing(O_RDONLY as u32));
+        assert!(opens_for_writing(O_WRONLY as u32));
+        assert!(opens_for_writing((O_RDWR | O_APPEND) as u32));
+        assert!(opens_for_writing((O_RDONLY | O_TRUNC) as u32));
+    }
+
+    #[test]
+    fn mount_options_become_fuse_args() {
+        let mut options = MountOptions::new(&"/mnt/gluster");
+        assert!(options.fuse_args().is_empty());
+
+        options.read_only = true;
+        options.allow_other = true;
+        options.default_permissions = true;
+        options.fsname = Some("server:/vol");
+        options.subtype = Some("glusterfs");
+        options.raw_options = vec!["noatime", "max_read=131072"];
+        let expected: Vec<OsString> = ["-o",
+                                       "ro",
+                                       "-o",
+                                       "allow_other",
+                                       "-o",
+                                       "default_permissions",
+                                       "-o",
+                                       "fsname=server:/vol",
+                                       "-o",
+                                       "subtype=glusterfs",
+                                       "-o",
+                                       "noatime",
+                                       "-o",
+                                       "max_read=131072"]
+            .iter()
+            .map(|arg| OsString::from(*arg))
+            .collect();
+        assert_eq!(options.fuse_args(), expected);
+    }
+
+    #[test]
+    fn dir_handles_balance_after_walk() {
+        let volume = FakeVolume::default();
+        let mut counter = HandleCounter::default();
+
+        // A recursive walk keeps parents open while it descends
+        let root = counter.opendir(&volume, Path::new("/")).unwrap();
+        for dir in &["/a", "/private", "/b", "/c"] {
+            if let Ok(fh) = counter.opendir(&volume, Path::new(dir)) {
+                assert_eq!(counter.outstanding(), 2);
+                let _ = counter.releasedir(&volume, fh);
+            }
+            assert_eq!(counter.outstanding(), volume.open.borrow().len() as u64);
+        }
+        let _ = counter.releasedir(&volume, root);
+        assert_eq!(counter.outstanding(), 0);
+        assert_eq!(counter,
+                   HandleCounter {
+                       opened: 4,
+                       closed: 4,
+                   });
+
+        // A release without a matching open doesn't wrap around
+        let _ = counter.releasedir(&volume, root);
+        assert_eq!(counter.outstanding(), 0);
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 pub struct MountOptions<'a> {
     path: &'a Path,
-    uid: u32,
-    gid: u32, // read_only: bool,
+    /// Mode to show for the mount root instead of the volume root's own
+    root_mode: Option<u16>,
+    /// Owner to show for the mount root instead of the volume root's own
+    root_uid: Option<u32>,
+    /// Group to show for the mount root instead of the volume root's own
+    root_gid: Option<u32>,
+    /// Mount read-only and refuse anything that would change the volume
+    read_only: bool,
+    /// Report the quota limit of the directory being queried from statfs
+    /// instead of the capacity of the whole volume.
+    quota_deem_statfs: bool,
+    /// Hide trusted.* xattrs from listxattr for callers that aren't root.
+    filter_xattr_namespaces: bool,
+    /// How long the kernel may cache attributes
+    attr_timeout: Timespec,
+    /// How long the kernel may cache a name it looked up
+    entry_timeout: Timespec,
+    /// How long the kernel may remember that a name doesn't exist.  0 turns
+    /// negative caching off.
+    negative_timeout: Timespec,
+    /// How long the supplementary groups read for a process are reused
+    gid_timeout: Timespec,
+    /// Let the server answer access() instead of evaluating the mode bits
+    /// locally.  Slower, but honors ACLs and anything else the server checks.
+    server_access: bool,
+    /// Let users other than the one mounting access the filesystem
+    allow_other: bool,
+    /// Like allow_other, but only for root
+    allow_root: bool,
+    /// Have the kernel check permissions from the mode bits before calling us
+    default_permissions: bool,
+    /// What `mount` and /proc/mounts show as the source
+    fsname: Option<&'a str>,
+    /// Shown as the filesystem type after "fuse."
+    subtype: Option<&'a str>,
+    /// Anything else to hand the kernel with -o
+    raw_options: Vec<&'a str>,
 }
 
 impl<'a> MountOptions<'a> {
     pub fn new<P: AsRef<Path>>(path: &P) -> MountOptions {
         MountOptions {
             path: path.as_ref(),
-            uid: unsafe { libc::getuid() } as u32,
-            gid: unsafe { libc::getgid() } as u32,
+            root_mode: None,
+            root_uid: None,
+            root_gid: None,
+            read_only: false,
+            quota_deem_statfs: false,
+            filter_xattr_namespaces: false,
+            attr_timeout: DEFAULT_TIMEOUT,
+            entry_timeout: DEFAULT_TIMEOUT,
+            negative_timeout: Timespec::new(0, 0),
+            gid_timeout: DEFAULT_GID_TIMEOUT,
+            server_access: false,
+            allow_other: false,
+            allow_root: false,
+            default_permissions: false,
+            fsname: None,
+            subtype: None,
+            raw_options: Vec::new(),
         }
     }
+
+    /// The options to mount with, as arguments for fuse::mount
+    pub fn fuse_args(&self) -> Vec<OsString> {
+        let mut options: Vec<String> = Vec::new();
+        if self.read_only {
+            options.push("ro".to_string());
+        }
+        if self.allow_other {
+            options.push("allow_other".to_string());
+        }
+        if self.allow_root {
+            options.push("allow_root".to_string());
+        }
+        if self.default_permissions {
+            options.push("default_permissions".to_string());
+        }
+        if let Some(fsname) = self.fsname {
+            options.push(format!("fsname={}", fsname));
+        }
+        if let Some(subtype) = self.subtype {
+            options.push(format!("subtype={}", subtype));
+        }
+        options.extend(self.raw_options.iter().map(|option| option.to_string()));
+
+        let mut args = Vec::new();
+        for option in options {
+            args.push(OsString::from("-o"));
+            args.push(OsString::from(option));
+        }
+        args
+    }
 }
 
 struct GlusterFilesystem {
     handle: Option<Gluster>,
+    /// gfapi calls gfapi-sys doesn't wrap
+    volume: Volume,
     inodes: InodeStore, /* inodes: HashMap<u64, INode<'a>>,
                          * root_path: PathBuf, */
+    root_mode: Option<u16>,
+    root_uid: Option<u32>,
+    root_gid: Option<u32>,
+    read_only: bool,
+    quota_deem_statfs: bool,
+    filter_xattr_namespaces: bool,
+    attr_timeout: Timespec,
+    entry_timeout: Timespec,
+    negative_timeout: Timespec,
+    server_access: bool,
+    locks: LockManager,
+    /// Directory handles from opendir, which releasedir has to close again
+    dir_handles: HandleCounter,
+    /// Changes other clients made, waiting to be applied to `inodes`
+    upcalls: Upcalls,
+    /// Who makes requests, and the means to make gluster calls as them
+    callers: Callers,
 }
 
 impl GlusterFilesystem {
@@ -88,480 +628,762 @@ impl GlusterFilesystem {
            port: u16,
            options: MountOptions)
            -> Result<(), std::io::Error> {
-        let handle = Gluster::connect(volume_name, server, port).unwrap();
+        let handle = Gluster::connect(volume_name, server, port)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        let volume = Volume::new(&handle).map_err(std::io::Error::from_raw_os_error)?;
+        let (root_object, root_stat) = volume.lookup(None, Path::new("/"))
+            .map_err(std::io::Error::from_raw_os_error)?;
+        let root = attr_from_stat(&root_stat)
+            .map(|attr| root_attr(attr, options.root_mode, options.root_uid, options.root_gid))
+            .ok_or_else(|| std::io::Error::from_raw_os_error(EIO))?;
+        let mut inodes = InodeStore::new(root);
+        inodes[1].object = Some(Rc::new(root_object));
+        let upcalls = Upcalls::new();
+        if let Err(errno) = upcalls.register(&volume) {
+            warn!("Not registered for upcalls, so changes other clients make show once the \
+                   cache times out: errno {}",
+                  errno);
+        }
+        let daemon = Credentials::of_daemon();
         let gfs = GlusterFilesystem {
             handle: Some(handle),
-            inodes: InodeStore::new(0o550, options.uid, options.gid),
+            volume: volume,
+            inodes: inodes,
+            root_mode: options.root_mode,
+            root_uid: options.root_uid,
+            root_gid: options.root_gid,
+            read_only: options.read_only,
+            quota_deem_statfs: options.quota_deem_statfs,
+            filter_xattr_namespaces: options.filter_xattr_namespaces,
+            attr_timeout: options.attr_timeout,
+            entry_timeout: options.entry_timeout,
+            negative_timeout: options.negative_timeout,
+            server_access: options.server_access,
+            locks: LockManager::new(daemon.clone()),
+            dir_handles: HandleCounter::default(),
+            upcalls: upcalls,
+            callers: Callers::new(Rc::new(Gfapi), daemon, options.gid_timeout),
         };
-        fuse::mount(gfs, &options.path, &[])
-    }
-    fn stat(&self, path: &Path) -> Result<FileAttr, String> {
-        let stat = self.handle().stat(path).map_err(|e| e.to_string())?;
-
-        let device_type = filetype_from_mode(stat.st_mode).ok_or(
-            format!("Unable to determine file type of: {}", stat.st_mode))?;
-        Ok(FileAttr {
-            ino: stat.st_ino,
-            size: stat.st_size as u64,
-            blocks: stat.st_blocks as u64,
-            atime: Timespec {
-                sec: stat.st_atime,
-                nsec: stat.st_atime_nsec as i32,
-            },
-            mtime: Timespec {
-                sec: stat.st_mtime,
-                nsec: stat.st_mtime_nsec as i32,
-            },
-            ctime: Timespec {
-                sec: stat.st_ctime,
-                nsec: stat.st_ctime_nsec as i32,
-            },
-            crtime: Timespec { sec: 1, nsec: 0 },
-            kind: device_type,
-            // TODO: Extract the permissions from the mode_t field
-            perm: 0o755,
-            nlink: stat.st_nlink as u32,
-            uid: stat.st_uid,
-            gid: stat.st_gid,
-            rdev: stat.st_rdev as u32,
-            flags: 0,
-        })
+        let args = options.fuse_args();
+        let args: Vec<&OsStr> = args.iter().map(|arg| arg.as_os_str()).collect();
+        fuse::mount(gfs, &options.path, &args)
+    }
+    /// Make gluster calls as the caller of `req` until the guard is dropped,
+    /// so the server checks permissions and charges quota against them.
+    fn as_caller(&mut self, req: &Request) -> Result<Guard, c_int> {
+        let caller = self.callers.of_request(req);
+        self.callers.switch_to(&caller)
+    }
+
+    fn stat(&self, path: &Path) -> Result<FileAttr, c_int> {
+        // lstat so symlinks show up as links instead of whatever they point at
+        let stat = self.handle().lsstat(path).errno("stat")?;
+        file_attr(path, &stat)
+    }
+
+    /// Name of a known inode, which is what the inode store is keyed by
+    fn path_of(&self, ino: u64) -> Result<PathBuf, c_int> {
+        self.inodes
+            .get(ino)
+            .and_then(|inode| inode.path())
+            .map(|path| path.to_path_buf())
+            .ok_or(ENOENT)
+    }
+
+    /// Name of `name` inside the directory `parent`
+    fn child_path(&self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        self.path_of(parent).map(|path| path.join(name))
+    }
+
+    /// gfapi's handle on `ino`, which keeps reaching the same file when
+    /// another client renames it or one of its parents.  Entries readdir
+    /// cached haven't been looked up yet, so the first use of one looks it up
+    /// by name under its parent.
+    fn object(&mut self, ino: u64) -> Result<Rc<Object>, c_int> {
+        let path = match self.inodes.get(ino) {
+            Some(inode) => {
+                if let Some(ref object) = inode.object {
+                    return Ok(object.clone());
+                }
+                inode.path().map(|path| path.to_path_buf()).ok_or(ENOENT)?
+            }
+            None => return Err(ENOENT),
+        };
+        let parent = path.parent()
+            .and_then(|dir| self.inodes.get_by_path(dir))
+            .map(|dir| dir.attr.ino)
+            .ok_or(ENOENT)?;
+        let name = path.file_name().ok_or(ENOENT)?;
+        let parent_object = self.object(parent)?;
+        let (object, _) = self.volume
+            .lookup(Some(&parent_object), Path::new(name))
+            .map_err(|errno| {
+                debug!("lookup of {} err: errno {}", path.display(), errno);
+                errno
+            })?;
+        let gfid = object.gfid()?;
+        let object = Rc::new(object);
+        if let Some(inode) = self.inodes.get_mut(ino) {
+            inode.gfid = Some(gfid);
+            inode.object = Some(object.clone());
+        }
+        Ok(object)
+    }
+
+    /// The path gfapi should use to reach `ino`, for the calls that have no
+    /// object handle variant.  This goes through gluster's virtual /.gfid
+    /// directory, so like the handle it survives renames.
+    fn resolve(&mut self, ino: u64) -> Result<PathBuf, c_int> {
+        if let Some(path) = gfid_path_of(&self.inodes, ino) {
+            return Ok(path);
+        }
+        // Looking the file up learns its gfid
+        self.object(ino)?;
+        gfid_path_of(&self.inodes, ino).ok_or(ENOENT)
+    }
+
+    /// The path gfapi should use for `name` inside the directory `parent`
+    fn resolve_child(&mut self, parent: u64, name: &OsStr) -> Result<PathBuf, c_int> {
+        self.resolve(parent).map(|path| path.join(name))
+    }
+
+    /// Record a file that was just looked up or created as `name` inside
+    /// `parent`, along with gfapi's handle on it.
+    fn add_child(&mut self,
+                 parent: u64,
+                 name: &OsStr,
+                 object: Object,
+                 stat: &libc::stat)
+                 -> Result<FileAttr, c_int> {
+        let path = self.child_path(parent, name)?;
+        let file_attr = file_attr(&path, stat)?;
+        let gfid = object.gfid()?;
+        self.inodes.insert_metadata(&path, &file_attr);
+        if let Some(inode) = self.inodes.get_mut(file_attr.ino) {
+            inode.gfid = Some(gfid);
+            inode.object = Some(Rc::new(object));
+        }
+        Ok(file_attr)
+    }
+
+    /// Stat `name` inside `parent` and record what we found in the inode store.
+    fn lookup_child(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let parent_object = self.object(parent)?;
+        let (object, stat) = self.volume.lookup(Some(&parent_object), Path::new(name))?;
+        self.add_child(parent, name, object, &stat)
+    }
+
+    /// lookup_child for an entry that is about to be handed to the kernel.
+    /// Every entry reply costs the kernel a lookup it later returns via forget.
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> Result<FileAttr, c_int> {
+        let file_attr = self.lookup_child(parent, name)?;
+        self.inodes.lookup(file_attr.ino);
+        Ok(file_attr)
+    }
+
+    /// add_child for an entry that is about to be handed to the kernel
+    fn new_entry(&mut self,
+                 parent: u64,
+                 name: &OsStr,
+                 object: Object,
+                 stat: &libc::stat)
+                 -> Result<FileAttr, c_int> {
+        let file_attr = self.add_child(parent, name, object, stat)?;
+        self.inodes.lookup(file_attr.ino);
+        Ok(file_attr)
+    }
+
+    /// Stat a known inode again and update its cached attributes.
+    fn refresh(&mut self, ino: u64) -> Result<FileAttr, c_int> {
+        let object = self.object(ino)?;
+        let stat = self.volume.stat(&object)?;
+        let mut file_attr = file_attr(&self.path_of(ino).unwrap_or_default(), &stat)?;
+        if ino == 1 {
+            file_attr = root_attr(file_attr, self.root_mode, self.root_uid, self.root_gid);
+        }
+        if let Some(inode) = self.inodes.get_mut(ino) {
+            inode.attr = file_attr;
+            inode.cached_at = time::get_time();
+        }
+        Ok(file_attr)
+    }
+
+    /// Apply the changes requested by setattr, stopping at the first failure.
+    /// Ownership goes first since chown may clear setuid/setgid, and times go
+    /// last since truncating bumps mtime.  When the caller has the file open
+    /// the fd based calls are used so permissions on the path don't matter.
+    fn set_attributes(&self,
+                      path: &Path,
+                      fh: Option<u64>,
+                      mode: Option<u32>,
+                      uid: Option<u32>,
+                      gid: Option<u32>,
+                      size: Option<u64>,
+                      atime: Option<Timespec>,
+                      mtime: Option<Timespec>)
+                      -> Result<(), c_int> {
+        let fd = fh.map(|fh| fh as *mut Struct_glfs_fd);
+
+        if uid.is_some() || gid.is_some() {
+            // -1 leaves that id unchanged
+            let uid = uid.unwrap_or(u32::max_value());
+            let gid = gid.unwrap_or(u32::max_value());
+            let result = match fd {
+                Some(fd) => self.handle().fchown(fd, uid, gid),
+                None => self.handle().chown(path, uid, gid),
+            };
+            result.errno("chown")?;
+        }
+
+        if let Some(file_mode) = mode {
+            let result = match fd {
+                Some(fd) => self.handle().fchmod(fd, file_mode),
+                None => self.handle().chmod(path, file_mode),
+            };
+            result.errno("chmod")?;
+        }
+
+        if let Some(new_size) = size {
+            let result = match fd {
+                Some(fd) => self.handle().ftruncate(fd, new_size as i64),
+                None => self.handle().truncate(path, new_size as i64),
+            };
+            result.errno("truncate")?;
+        }
+
+        if atime.is_some() || mtime.is_some() {
+            let times = [utime_spec(atime), utime_spec(mtime)];
+            let result = match fd {
+                Some(fd) => self.handle().futimens(fd, &times),
+                None => self.handle().utimens(path, &times),
+            };
+            result.errno("utimens")?;
+        }
+        Ok(())
     }
 
+    /// fsync or fdatasync the gluster fd behind a FUSE file handle.
+    fn sync_fd(&self, fh: u64, datasync: bool) -> Result<(), c_int> {
+        let fd = fh as *mut Struct_glfs_fd;
+        let result = if datasync {
+            self.handle().fdatasync(fd)
+        } else {
+            self.handle().fsync(fd)
+        };
+        result.errno("fsync")
+    }
 
     fn handle(&self) -> &Gluster {
         self.handle.as_ref().unwrap()
     }
 }
 
+/// The guard `GlusterFilesystem::as_caller` returns, or else reply with the
+/// error and return from the handler.
+macro_rules! as_caller {
+    ($fs:expr, $req:expr, $reply:expr) => {
+        match $fs.as_caller($req) {
+            Ok(guard) => guard,
+            Err(errno) => {
+                $reply.error(errno);
+                return;
+            }
+        }
+    }
+}
 
 impl Filesystem for GlusterFilesystem {
-    fn getattr(&mut self, _req: &Request, ino: u64, reply: ReplyAttr) {
+    fn getattr(&mut self, req: &Request, ino: u64, reply: ReplyAttr) {
         trace!("getattr(ino={})", ino);
-        match self.inodes.get(ino) {
-            Some(inode) => reply.attr(&TTL, &inode.attr),
+        let _caller = as_caller!(self, req, reply);
+        self.upcalls.apply_pending(&mut self.inodes);
+        let cached = match self.inodes.get(ino) {
+            Some(inode) => {
+                if inode.is_fresh(self.attr_timeout) {
+                    Some(inode.attr)
+                } else {
+                    None
+                }
+            }
             None => {
                 trace!("getattr ENOENT: {}", ino);
                 reply.error(ENOENT);
+                return;
             }
         };
+        match cached.map(Ok).unwrap_or_else(|| self.refresh(ino)) {
+            Ok(file_attr) => reply.attr(&self.attr_timeout, &file_attr),
+            Err(errno) => reply.error(errno),
+        }
     }
 
-    fn lookup(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
+    fn lookup(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEntry) {
         trace!("lookup(parent={}, name=\"{}\")",
                parent,
                name.to_string_lossy());
+        let _caller = as_caller!(self, req, reply);
+        self.upcalls.apply_pending(&mut self.inodes);
+        self.inodes.expire_prefetched(self.entry_timeout);
+
+        // readdir caches the attributes of everything it lists, so the lookup
+        // per entry that `ls -l` does right after doesn't need a round trip.
+        let cached = self.inodes
+            .child(parent, name)
+            .and_then(|inode| if inode.is_fresh(self.entry_timeout) {
+                Some(inode.attr)
+            } else {
+                None
+            });
+        if let Some(file_attr) = cached {
+            self.inodes.lookup(file_attr.ino);
+            reply.entry(&self.entry_timeout, &file_attr, 0);
+            return;
+        }
 
-        // Clone until MIR NLL lands
-        // match self.inodes.child(parent, &name).cloned() {
-        // Some(child_inode) => reply.entry(&TTL, &child_inode.attr, 0),
-        // None => {
-        // Clone until MIR NLL lands
-        let parent_inode = self.inodes[parent].clone();
-        let child_path = parent_inode.path.join(&name);
-        match self.stat(&child_path) {
-            Ok(file_attr) => {
-                let inode = self.inodes.insert_metadata(&child_path, &file_attr).unwrap();
-                reply.entry(&TTL, &inode.attr, 0)
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
-            Err(e) => {
-                error!("lookup err: {:?}", e);
-                reply.error(ENOENT)
+        };
+        match self.lookup_entry(parent, name) {
+            Ok(file_attr) => reply.entry(&self.entry_timeout, &file_attr, 0),
+            Err(ENOENT) => {
+                // Removed behind our back, so whatever is cached under the
+                // name is stale
+                self.inodes.remove_path(&path);
+                if self.negative_timeout != Timespec::new(0, 0) {
+                    // The kernel doesn't forget negative entries, so no
+                    // lookup is counted for this one
+                    reply.entry(&self.negative_timeout, &negative_entry(), 0)
+                } else {
+                    reply.error(ENOENT);
+                }
             }
+            Err(errno) => reply.error(errno),
         }
-        // }
-        // }
     }
 
     fn readdir(&mut self,
-               _req: &Request,
-               _ino: u64,
-               _fh: u64,
-               _offset: u64,
+               req: &Request,
+               ino: u64,
+               fh: u64,
+               offset: u64,
                mut reply: ReplyDirectory) {
-        trace!("readdir(ino={}, fh={}, offset={})", _ino, _fh, _offset);
-        let d = GlusterDirectory { dir_handle: _fh as *mut Struct_glfs_fd };
-        let mut offset: u64 = 0;
-
-        for dir_entry in d {
-            trace!("Dir_entry: {:?}", dir_entry);
-            let device_type = filetype_from_uchar(dir_entry.file_type);
-            match device_type {
-                Some(d_type) => {
-                    // This returns true if the buffer is full
-                    let full = reply.add(dir_entry.inode, offset, d_type, dir_entry.path);
-                    if full {
-                        return reply.ok();
+        trace!("readdir(ino={}, fh={}, offset={})", ino, fh, offset);
+        let _caller = as_caller!(self, req, reply);
+        let dir_path = match self.path_of(ino) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        self.inodes.expire_prefetched(self.entry_timeout);
+
+        // The offsets handed to the kernel are telldir() cookies, so when the
+        // reply fills up the next call resumes right after the last entry that
+        // made it in.  Offset 0 is the start of the directory.
+        let mut added = false;
+        for result in DirectoryPlus::new(fh as *mut Struct_glfs_fd, offset) {
+            let dir_entry = match result {
+                Ok(dir_entry) => dir_entry,
+                Err(errno) => {
+                    // Hand back what we have, the next call will hit the error again
+                    if !added {
+                        reply.error(errno);
+                        return;
                     }
-                    offset += 1;
+                    break;
                 }
+            };
+            trace!("Dir_entry: {:?} ino={}", dir_entry.name, dir_entry.inode);
+            let is_dot = dir_entry.name == "." || dir_entry.name == "..";
+            let child_path = dir_path.join(&dir_entry.name);
+
+            // readdirplus fetched the attributes along with the name.  Caching
+            // them here lets the lookups that usually follow skip the server.
+            let attr = dir_entry.stat.as_ref().and_then(attr_from_stat);
+            if let (false, Some(ref attr)) = (is_dot, attr) {
+                self.inodes.insert_prefetched(&child_path, attr);
+            }
+
+            let kind = match filetype_from_uchar(dir_entry.file_type) {
+                Some(kind) => kind,
                 None => {
-                    return reply.error(ENOSYS);
+                    // DT_UNKNOWN, which FileType can't express, so use the
+                    // attributes.  An entry that can't be stat'd anymore is gone.
+                    let looked_up = match attr {
+                        Some(attr) => Ok(attr),
+                        None => {
+                            self.resolve_child(ino, &dir_entry.name)
+                                .and_then(|path| self.stat(&path))
+                        }
+                    };
+                    match looked_up {
+                        Ok(attr) => attr.kind,
+                        Err(_) => continue,
+                    }
                 }
+            };
+            // The kernel wants its own nodeid for "."
+            let entry_ino = if dir_entry.name == "." {
+                ino
+            } else {
+                dir_entry.inode
+            };
+            // This returns true if the buffer is full
+            if reply.add(entry_ino, dir_entry.cookie, kind, &dir_entry.name) {
+                break;
             }
+            added = true;
         }
         reply.ok();
     }
-    fn opendir(&mut self, _req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
+    fn opendir(&mut self, req: &Request, ino: u64, _flags: u32, reply: ReplyOpen) {
         trace!("opendir(ino={})", ino);
-        match self.inodes.get(ino) {
-            Some(inode) => {
-                let path = &inode.path;
-                trace!("opendir current_path: {}", path.to_string_lossy());
-                let dir_handle = self.handle().opendir(path).unwrap();
-                reply.opened(dir_handle as u64, _flags);
+        let _caller = as_caller!(self, req, reply);
+        let path = match self.resolve(ino) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
-            None => reply.error(ENOENT),
+        };
+        trace!("opendir current_path: {}", path.to_string_lossy());
+        let handle = self.handle.as_ref().unwrap();
+        match self.dir_handles.opendir(handle, &path) {
+            Ok(dir_handle) => reply.opened(dir_handle, _flags),
+            Err(errno) => reply.error(errno),
         }
-
     }
-    fn releasedir(&mut self, _req: &Request, _ino: u64, _fh: u64, _flags: u32, reply: ReplyEmpty) {
+    fn releasedir(&mut self, _req: &Request, _ino: u64, fh: u64, _flags: u32, reply: ReplyEmpty) {
         trace!("releasedir(ino={})", _ino);
-        reply.error(ENOSYS);
+        let handle = self.handle.as_ref().unwrap();
+        match self.dir_handles.releasedir(handle, fh) {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 
-    fn open(&mut self, _req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
+    fn open(&mut self, req: &Request, ino: u64, flags: u32, reply: ReplyOpen) {
         trace!("open(ino={}, flags=0x{:x})", ino, flags);
+        if self.read_only && opens_for_writing(flags) {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
         // match flags & O_ACCMODE => O_RDONLY, O_WRONLY, O_RDWR
-        match self.inodes.get(ino) {
-            Some(inode) => {
-                let path = &inode.path;
-                trace!("open current_path: {}", path.to_string_lossy());
-                let file_handle = self.handle().open(path, flags as i32).unwrap();
+        let result = self.object(ino).and_then(|object| {
+            self.volume.open(&object, flags as i32).map_err(|errno| {
+                debug!("open err: errno {}", errno);
+                errno
+            })
+        });
+        match result {
+            Ok(file_handle) => {
+                self.locks.opened(file_handle as u64);
                 reply.opened(file_handle as u64, flags);
             }
-            None => reply.error(ENOENT),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        trace!("statfs(ino={})", _ino);
-        reply.error(ENOSYS);
+    fn statfs(&mut self, req: &Request, ino: u64, reply: ReplyStatfs) {
+        trace!("statfs(ino={})", ino);
+        let _caller = as_caller!(self, req, reply);
+        // The quota translator only clamps statvfs to a directory's limit when
+        // asked about that directory, so whole-volume numbers come from the root.
+        let path = if self.quota_deem_statfs {
+            match self.resolve(ino) {
+                Ok(path) => path,
+                Err(errno) => {
+                    reply.error(errno);
+                    return;
+                }
+            }
+        } else {
+            PathBuf::from("/")
+        };
+
+        match self.volume.statvfs(&path) {
+            Ok(stat) => {
+                reply.statfs(stat.f_blocks as u64,
+                             stat.f_bfree as u64,
+                             stat.f_bavail as u64,
+                             stat.f_files as u64,
+                             stat.f_ffree as u64,
+                             stat.f_bsize as u32,
+                             stat.f_namemax as u32,
+                             stat.f_frsize as u32);
+            }
+            Err(errno) => reply.error(errno),
+        }
     }
     fn setattr(&mut self,
-               _req: &Request,
+               req: &Request,
                ino: u64,
                mode: Option<u32>,
                uid: Option<u32>,
                gid: Option<u32>,
-               _size: Option<u64>,
+               size: Option<u64>,
                atime: Option<Timespec>,
                mtime: Option<Timespec>,
-               _fh: Option<u64>,
+               fh: Option<u64>,
                _crtime: Option<Timespec>,
                _chgtime: Option<Timespec>,
                _bkuptime: Option<Timespec>,
                _flags: Option<u32>,
                reply: ReplyAttr) {
         trace!("setattr(ino={})", ino);
-
-        let path = match self.inodes.get(ino) {
-            Some(inode) => inode.path.clone(),
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        let mut times: [timespec; 2] = [timespec {
-                                            tv_sec: 0,
-                                            tv_nsec: 0,
-                                        },
-                                        timespec {
-                                            tv_sec: 0,
-                                            tv_nsec: 0,
-                                        }];
-        if let Some(access_time) = atime {
-            times[0] = timespec {
-                tv_sec: access_time.sec,
-                tv_nsec: access_time.nsec as i64,
-            };
-        }
-        if let Some(m_time) = mtime {
-            times[1] = timespec {
-                tv_sec: m_time.sec,
-                tv_nsec: m_time.nsec as i64,
-            };
+        if self.read_only {
+            reply.error(EROFS);
+            return;
         }
+        let _caller = as_caller!(self, req, reply);
 
-        // Change the access times if requested
-        match self.handle().utimens(&path, &times) {
-            Ok(_) => {
-                // reply.attr(&TTL, &inode.attr);
-            }
-            Err(e) => {
-                error!("utimens err: {:?}", e);
-                // reply.error(ENOENT);
+        let path = match self.resolve(ino) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
         };
 
-        // Change the file mode if requested
-        if let Some(file_mode) = mode {
-            match self.handle().chmod(&path, file_mode) {
-                Ok(_) => {
-                    // reply.attr(&TTL, &inode.attr);
-                }
-                Err(e) => {
-                    error!("chmod err: {:?}", e);
-                    // reply.error(ENOENT);
-                }
-            }
+        if let Err(errno) = self.set_attributes(&path, fh, mode, uid, gid, size, atime, mtime) {
+            reply.error(errno);
+            return;
         }
-
-        // Change the ownership if both uid and gid are specified
-        // TODO: What if only one is set?
-        if uid.is_some() && gid.is_some() {
-            match self.handle().chown(&path, uid.unwrap(), gid.unwrap()) {
-                Ok(_) => {
-                    // reply.attr(&TTL, &inode.attr);
-                }
-                Err(e) => {
-                    error!("chown err: {:?}", e);
-                    // reply.error(ENOENT);
-                }
-            }
+        if let (Some(new_size), Some(inode)) = (size, self.inodes.get_mut(ino)) {
+            inode.attr.size = new_size;
         }
 
         // Finally stat and return
-        match self.stat(&path) {
-            Ok(file_attr) => {
-                let inode = self.inodes.insert_metadata(&path, &file_attr).unwrap();
-                reply.attr(&TTL, &inode.attr)
-            }
-            Err(e) => {
-                error!("getattr lookup err: {:?}", e);
-                reply.error(ENOENT)
-            }
+        match self.refresh(ino) {
+            Ok(file_attr) => reply.attr(&self.attr_timeout, &file_attr),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn mknod(&mut self,
-             _req: &Request,
+             req: &Request,
              parent: u64,
              name: &OsStr,
              _mode: u32,
              _rdev: u32,
              reply: ReplyEntry) {
         trace!("mknod(parent={}, name={:?})", parent, name);
-        let path = self.inodes[parent].path.join(&name);
-        match self.handle().mknod(&path, _mode, _rdev as u64) {
-            Ok(()) => {
-                match self.stat(&path) {
-                    Ok(file_attr) => {
-                        let inode = self.inodes.insert_metadata(&path, &file_attr).unwrap();
-                        reply.entry(&TTL, &inode.attr, file_attr.size)
-                    }
-                    Err(e) => {
-                        error!("mknod lookup err: {:?}", e);
-                        reply.error(ENOENT)
-                    }
-                }
-            }
-            Err(e) => {
-                error!("mknod err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.object(parent).and_then(|dir| {
+            let (object, stat) = self.volume.mknod(&dir, name, _mode, rdev_from_fuse(_rdev))?;
+            self.new_entry(parent, name, object, &stat)
+        });
+        match result {
+            Ok(file_attr) => reply.entry(&self.entry_timeout, &file_attr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn mkdir(&mut self, _req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
+    fn mkdir(&mut self, req: &Request, parent: u64, name: &OsStr, _mode: u32, reply: ReplyEntry) {
         trace!("mkdir(parent={}, name={:?})", parent, name);
-        let path = self.inodes[parent].path.join(&name);
-        match self.handle().mkdir(&path, _mode) {
-            Ok(()) => {
-                match self.stat(&path) {
-                    Ok(file_attr) => {
-                        let inode = self.inodes.insert_metadata(&path, &file_attr).unwrap();
-                        reply.entry(&TTL, &inode.attr, file_attr.size)
-                    }
-                    Err(e) => {
-                        error!("mkdir lookup err: {:?}", e);
-                        reply.error(ENOENT)
-                    }
-                }
-            }
-            Err(e) => {
-                error!("mkdir err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.object(parent).and_then(|dir| {
+            let (object, stat) = self.volume.mkdir(&dir, name, _mode)?;
+            self.new_entry(parent, name, object, &stat)
+        });
+        match result {
+            Ok(file_attr) => reply.entry(&self.entry_timeout, &file_attr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn forget(&mut self, _req: &Request, _ino: u64, _nlookup: u64) {
-        trace!("forget(ino={:?})", _ino);
-
+    fn forget(&mut self, _req: &Request, ino: u64, nlookup: u64) {
+        trace!("forget(ino={:?}, nlookup={})", ino, nlookup);
+        self.inodes.forget(ino, nlookup);
+        trace!("forget: {} inodes cached", self.inodes.len());
     }
 
-    fn readlink(&mut self, _req: &Request, _ino: u64, reply: ReplyData) {
-        trace!("readlink(ino={:?})", _ino);
-        reply.error(ENOSYS);
+    fn readlink(&mut self, req: &Request, ino: u64, reply: ReplyData) {
+        trace!("readlink(ino={:?})", ino);
+        let _caller = as_caller!(self, req, reply);
+        match self.inodes.get(ino) {
+            Some(inode) => {
+                if inode.attr.kind != FileType::Symlink {
+                    reply.error(EINVAL);
+                    return;
+                }
+            }
+            None => {
+                reply.error(ENOENT);
+                return;
+            }
+        }
+        let mut target: Vec<u8> = vec![0; PATH_MAX as usize];
+        let result = self.object(ino)
+            .and_then(|object| self.volume.readlink(&object, &mut target));
+        match result {
+            Ok(_) => reply.data(link_target(&target)),
+            Err(errno) => reply.error(errno),
+        }
     }
 
-    fn unlink(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn unlink(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("unlink(name={:?})", name);
-        let target = match self.inodes.child(parent, name) {
-            Some(inode) => inode.clone(),
-            None => {
-                // Trying to remove a inode that doesn't exist in the cache
-                reply.error(ENOENT);
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let path = match self.child_path(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
         };
-
-        match self.handle().unlink(&target.path) {
+        let result = self.object(parent).and_then(|dir| self.volume.unlink(&dir, name));
+        match result {
             Ok(_) => {
-                self.inodes.remove(target.attr.ino);
+                self.inodes.remove_path(&path);
                 reply.ok();
             }
-            Err(e) => {
-                error!("unlink err: {:?}", e);
-                reply.error(ENOENT);
-            }
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn rmdir(&mut self, _req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
+    fn rmdir(&mut self, req: &Request, parent: u64, name: &OsStr, reply: ReplyEmpty) {
         trace!("rmdir(name={:?})", name);
-        let parent_inode = self.inodes[parent].clone();
-        let target = parent_inode.path.join(&name);
-        match self.handle().rmdir(&target) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("rmdir err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.child_path(parent, name).and_then(|target| {
+            let dir = self.object(parent)?;
+            self.volume.unlink(&dir, name)?;
+            self.inodes.remove_path(&target);
+            Ok(())
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn symlink(&mut self,
-               _req: &Request,
+               req: &Request,
                parent: u64,
                name: &OsStr,
                link: &Path,
                reply: ReplyEntry) {
         trace!("symlink(name={:?})", name);
-        let parent_inode = self.inodes[parent].clone();
-        // TODO Is this correct?
-        let target = parent_inode.path.join(&name);
-
-        match self.handle().symlink(&target, &link) {
-            Ok(_) => {
-                match self.stat(&target) {
-                    Ok(file_attr) => {
-                        // TODO Is this correct?
-                        let inode = self.inodes.insert_metadata(&target, &file_attr).unwrap();
-                        reply.entry(&TTL, &inode.attr, file_attr.size)
-                    }
-                    Err(e) => {
-                        error!("symlink lookup err: {:?}", e);
-                        reply.error(ENOENT)
-                    }
-                }
-            }
-            Err(e) => {
-                error!("symlink err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        // The new link lives at parent/name and points at `link`
+        let result = self.object(parent).and_then(|dir| {
+            let (object, stat) = self.volume.symlink(&dir, name, link)?;
+            self.new_entry(parent, name, object, &stat)
+        });
+        match result {
+            Ok(file_attr) => reply.entry(&self.entry_timeout, &file_attr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
     /// Rename a file.
     fn rename(&mut self,
-              _req: &Request,
+              req: &Request,
               parent: u64,
               name: &OsStr,
               newparent: u64,
               newname: &OsStr,
               reply: ReplyEmpty) {
         trace!("rename(name={:?} to {:?})", name, newname);
-        let parent_inode = self.inodes[parent].clone();
-        let child_old_path = parent_inode.path.join(&name);
-
-        let new_parent_inode = self.inodes[newparent].clone();
-        let new_child_path = new_parent_inode.path.join(&newname);
-        match self.handle().rename(&child_old_path, &new_child_path) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("rename err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.child_path(parent, name).and_then(|old_path| {
+            let new_path = self.child_path(newparent, newname)?;
+            let old_dir = self.object(parent)?;
+            let new_dir = self.object(newparent)?;
+            self.volume.rename(&old_dir, name, &new_dir, newname)?;
+            self.inodes.rename(&old_path, &new_path);
+            Ok(())
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
     }
 
     /// Create a hard link.
     fn link(&mut self,
-            _req: &Request,
+            req: &Request,
             ino: u64,
             newparent: u64,
             newname: &OsStr,
             reply: ReplyEntry) {
         trace!("link(ino={:?})", ino);
-        let old_path = match self.inodes.get(ino) {
-            Some(inode) => inode.path.clone(),
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-
-        let new_inode = self.inodes[newparent].clone();
-        let new_path = new_inode.path.join(&newname);
-
-        match self.handle().link(&old_path, &new_path) {
-            Ok(_) => {
-                match self.stat(&new_path) {
-                    Ok(file_attr) => {
-                        let inode = self.inodes.insert_metadata(&new_path, &file_attr).unwrap();
-                        reply.entry(&TTL, &inode.attr, file_attr.size)
-                    }
-                    Err(e) => {
-                        error!("link lookup err: {:?}", e);
-                        reply.error(ENOENT)
-                    }
-                }
-            }
-            Err(e) => {
-                error!("link err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.object(ino).and_then(|object| {
+            let dir = self.object(newparent)?;
+            self.volume.link(&object, &dir, newname)?;
+            self.lookup_entry(newparent, newname)
+        });
+        match result {
+            Ok(file_attr) => reply.entry(&self.entry_timeout, &file_attr, 0),
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn read(&mut self,
-            _req: &Request,
+            req: &Request,
             _ino: u64,
             fh: u64,
             offset: u64,
             _size: u32,
             reply: ReplyData) {
         trace!("read(ino={:?})", _ino);
+        let _caller = as_caller!(self, req, reply);
 
         // TODO: Use a buffer pool
         let mut fill_buffer: Vec<u8> = Vec::with_capacity(_size as usize);
 
-        match self.handle().pread(fh as *mut Struct_glfs_fd,
-                                  &mut fill_buffer,
-                                  _size as usize,
-                                  offset as i64,
-                                  0) {
+        match self.handle()
+            .pread(fh as *mut Struct_glfs_fd,
+                   &mut fill_buffer,
+                   _size as usize,
+                   offset as i64,
+                   0)
+            .errno("read") {
             Ok(bytes_read) => {
                 fill_buffer.truncate(bytes_read as usize);
-                println!("bytes_read: {} {:?}", bytes_read, fill_buffer);
                 reply.data(&fill_buffer[..]);
             }
-            Err(e) => {
-                error!("read err: {:?}", e);
-                reply.error(ENOENT);
-            }
-
+            Err(errno) => reply.error(errno),
         }
     }
 
     fn write(&mut self,
-             _req: &Request,
+             req: &Request,
              ino: u64,
              fh: u64,
              offset: u64,
@@ -569,27 +1391,52 @@ impl Filesystem for GlusterFilesystem {
              flags: u32,
              reply: ReplyWrite) {
         trace!("write(ino={:?})", ino);
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
 
         // Should already have the file handle open here
-        match self.handle().pwrite(fh as *mut Struct_glfs_fd,
-                                   data,
-                                   data.len(),
-                                   offset as i64,
-                                   flags as i32) {
+        match self.handle()
+            .pwrite(fh as *mut Struct_glfs_fd,
+                    data,
+                    data.len(),
+                    offset as i64,
+                    flags as i32)
+            .errno("write") {
             Ok(bytes_written) => {
-                self.inodes.get_mut(ino).unwrap().attr.size += bytes_written as u64;
+                if let Some(inode) = self.inodes.get_mut(ino) {
+                    inode.attr.size += bytes_written as u64;
+                }
                 reply.written(bytes_written as u32);
             }
-            Err(e) => {
-                error!("write err: {:?}", e);
-                reply.error(EIO);
-            }
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
+    fn flush(&mut self, req: &Request, _ino: u64, fh: u64, lock_owner: u64, reply: ReplyEmpty) {
         trace!("flush(ino={:?})", _ino);
-        reply.ok();
+        let _caller = as_caller!(self, req, reply);
+        // gfapi has no glfs_flush but glfs_close sends a FLUSH fop before it drops
+        // the fd.  Closing a dup pushes out anything write-behind is still holding
+        // and hands back its errors while leaving the caller's fd open.
+        let dup_fd = match self.handle().dup(fh as *mut Struct_glfs_fd).fd("flush dup") {
+            Ok(fd) => fd,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        // The posix locks translator drops the flushing owner's locks, which
+        // is what POSIX wants from any close() by the lock holder.
+        if let Err(errno) = lock::set_owner(dup_fd, lock_owner) {
+            error!("flush set lock owner err: {}", errno);
+        }
+        match self.handle().close(dup_fd).errno("flush") {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn release(&mut self,
@@ -597,34 +1444,46 @@ impl Filesystem for GlusterFilesystem {
                _ino: u64,
                fh: u64,
                _flags: u32,
-               _lock_owner: u64,
+               lock_owner: u64,
                _flush: bool,
                reply: ReplyEmpty) {
         trace!("release(ino={:?})", _ino);
-        match self.handle().close(fh as *mut Struct_glfs_fd) {
+        if let Err(errno) = self.locks.released(fh, lock_owner) {
+            error!("release unlock err: {}", errno);
+        }
+        match self.handle().close(fh as *mut Struct_glfs_fd).errno("release") {
             Ok(_) => reply.ok(),
-            Err(_) => reply.error(EIO),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        trace!("fsync(ino={:?})", _ino);
-        reply.error(ENOSYS);
+    fn fsync(&mut self, req: &Request, _ino: u64, fh: u64, datasync: bool, reply: ReplyEmpty) {
+        trace!("fsync(ino={:?}, datasync={})", _ino, datasync);
+        let _caller = as_caller!(self, req, reply);
+        match self.sync_fd(fh, datasync) {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn fsyncdir(&mut self,
-                _req: &Request,
+                req: &Request,
                 _ino: u64,
-                _fh: u64,
-                _datasync: bool,
+                fh: u64,
+                datasync: bool,
                 reply: ReplyEmpty) {
-        trace!("fsyncdir(ino={:?})", _ino);
-        reply.error(ENOSYS);
+        trace!("fsyncdir(ino={:?}, datasync={})", _ino, datasync);
+        let _caller = as_caller!(self, req, reply);
+        // Directory handles from opendir are ordinary glfs fds
+        match self.sync_fd(fh, datasync) {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     /// Set an extended attribute.
     fn setxattr(&mut self,
-                _req: &Request,
+                req: &Request,
                 ino: u64,
                 name: &OsStr,
                 value: &[u8],
@@ -632,160 +1491,210 @@ impl Filesystem for GlusterFilesystem {
                 _position: u32,
                 reply: ReplyEmpty) {
         trace!("setxattr(ino={:?})", ino);
-        let path = match self.inodes.get(ino) {
-            Some(inode) => inode.path.clone(),
-            None => {
-                reply.error(ENOENT);
-                return;
-            }
-        };
-        match self.handle().setxattr(&path,
-                                     &name.to_string_lossy().into_owned(),
-                                     value,
-                                     flags as i32) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("setxattr err: {:?}", e);
-                reply.error(ENOENT);
-            }
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+        let result = self.resolve(ino).and_then(|path| {
+            self.handle()
+                .setxattr(&path,
+                          &name.to_string_lossy().into_owned(),
+                          value,
+                          flags as i32)
+                .errno("setxattr")
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn getxattr(&mut self, _req: &Request, ino: u64, name: &OsStr, _size: u32, reply: ReplyXattr) {
+    fn getxattr(&mut self, req: &Request, ino: u64, name: &OsStr, _size: u32, reply: ReplyXattr) {
         trace!("getxattr(ino={:?})", ino);
+        let _caller = as_caller!(self, req, reply);
 
-        let path = match self.inodes.get(ino) {
-            Some(inode) => inode.path.clone(),
-            None => {
-                reply.error(ENOENT);
+        let path = match self.resolve(ino) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
         };
         trace!("getxattr path: {:?}, name: {}",
                path,
                name.to_string_lossy());
-        match self.handle().getxattr(&path, &name.to_string_lossy().into_owned()) {
-            Ok(data) => {
-                match self.stat(&path) {
-                    Ok(file_attr) => {
-                        self.inodes.insert_metadata(&path, &file_attr).unwrap();
-                        if data.len() as u32 > _size {
-                            reply.error(ERANGE);
-                            return;
-                        }
-                        if _size == 0 {
-                            reply.size(data.len() as u32);
-                        } else {
-                            reply.data(&data.as_bytes());
-                        }
-                    }
-                    Err(e) => {
-                        error!("getxattr lookup err: {:?}", e);
-                        reply.error(ENODATA)
-                    }
-                }
-            }
-            Err(e) => {
-                error!("getxattr err: {:?}", e);
-                reply.error(ENODATA);
-            }
+        match self.volume.getxattr(&path, name) {
+            Ok(data) => reply_xattr(reply, &data, _size),
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn listxattr(&mut self, _req: &Request, _ino: u64, _size: u32, reply: ReplyXattr) {
-        trace!("listxattr(ino={:?})", _ino);
-        reply.error(ENOSYS);
-    }
-
-    fn removexattr(&mut self, _req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
-        trace!("removexattr(ino={:?})", ino);
+    fn listxattr(&mut self, req: &Request, ino: u64, size: u32, reply: ReplyXattr) {
+        trace!("listxattr(ino={:?}, size={})", ino, size);
+        let _caller = as_caller!(self, req, reply);
 
-        let path = match self.inodes.get(ino) {
-            Some(inode) => inode.path.clone(),
-            None => {
-                reply.error(ENOENT);
+        let path = match self.resolve(ino) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
                 return;
             }
         };
-
-        match self.handle().removexattr(&path, &name.to_string_lossy().into_owned()) {
-            Ok(_) => {
-                reply.ok();
-            }
-            Err(e) => {
-                error!("removexattr err: {:?}", e);
-                reply.error(ENODATA);
+        match self.volume.listxattr(&path) {
+            Ok(names) => {
+                // The kernel expects the names back to back, each nul terminated
+                let mut list: Vec<u8> = Vec::new();
+                for name in names {
+                    if self.filter_xattr_namespaces && req.uid() != 0 &&
+                       name.as_bytes().starts_with(b"trusted.") {
+                        continue;
+                    }
+                    list.extend_from_slice(name.as_bytes());
+                    list.push(0);
+                }
+                reply_xattr(reply, &list, size);
             }
+            Err(errno) => reply.error(errno),
         }
     }
 
-    fn access(&mut self, _req: &Request, _ino: u64, _mask: u32, reply: ReplyEmpty) {
-        trace!("access(ino={:?})", _ino);
-        reply.error(ENOSYS);
+    fn removexattr(&mut self, req: &Request, ino: u64, name: &OsStr, reply: ReplyEmpty) {
+        trace!("removexattr(ino={:?})", ino);
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
+
+        let result = self.resolve(ino).and_then(|path| {
+            self.handle()
+                .removexattr(&path, &name.to_string_lossy().into_owned())
+                .errno("removexattr")
+        });
+        match result {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
+    }
+
+    fn access(&mut self, req: &Request, ino: u64, mask: u32, reply: ReplyEmpty) {
+        trace!("access(ino={:?}, mask={:o})", ino, mask);
+        if self.read_only && mask as c_int & W_OK != 0 {
+            reply.error(EROFS);
+            return;
+        }
+        let guard = as_caller!(self, req, reply);
+
+        if self.server_access {
+            let result = self.object(ino)
+                .and_then(|object| self.volume.access(&object, mask as i32));
+            match result {
+                Ok(_) => reply.ok(),
+                Err(errno) => reply.error(errno),
+            }
+            return;
+        }
+
+        let cached = self.inodes
+            .get(ino)
+            .and_then(|inode| if inode.is_fresh(self.attr_timeout) {
+                Some(inode.attr)
+            } else {
+                None
+            });
+        match cached.map(Ok).unwrap_or_else(|| self.refresh(ino)) {
+            Ok(ref file_attr) if guard.caller().may_access(file_attr, mask) => reply.ok(),
+            Ok(_) => reply.error(EACCES),
+            Err(errno) => reply.error(errno),
+        }
     }
 
     fn create(&mut self,
-              _req: &Request,
+              req: &Request,
               parent: u64,
               name: &OsStr,
               mode: u32,
               flags: u32,
               reply: ReplyCreate) {
         trace!("create(name={:?})", name);
+        if self.read_only {
+            reply.error(EROFS);
+            return;
+        }
+        let _caller = as_caller!(self, req, reply);
 
-        // Clone until MIR NLL lands
-        let parent_inode = self.inodes[parent].clone();
-        let child_path = parent_inode.path.join(&name);
-        match self.handle().create(&child_path, flags as i32, mode) {
-            Ok(fh) => {
-                match self.stat(&child_path) {
-                    Ok(file_attr) => {
-                        let inode = self.inodes.insert_metadata(&child_path, &file_attr).unwrap();
-                        reply.created(&TTL, &inode.attr, file_attr.size, fh as u64, flags)
-                    }
-                    Err(e) => {
-                        error!("create lookup err: {:?}", e);
-                        reply.error(ENOENT)
-                    }
-                }
+        let child_path = match self.resolve_child(parent, name) {
+            Ok(path) => path,
+            Err(errno) => {
+                reply.error(errno);
+                return;
             }
-            Err(e) => {
-                error!("create err: {:?}", e);
-                reply.error(ENOENT);
+        };
+        let fh = match self.handle().create(&child_path, flags as i32, mode).errno("create") {
+            Ok(fh) => fh,
+            Err(errno) => {
+                reply.error(errno);
+                return;
+            }
+        };
+        match self.lookup_entry(parent, name) {
+            Ok(file_attr) => {
+                self.locks.opened(fh as u64);
+                reply.created(&self.entry_timeout, &file_attr, 0, fh as u64, flags)
+            }
+            Err(errno) => {
+                // Don't leak the fd the kernel will never know about
+                let _ = self.handle().close(fh);
+                reply.error(errno)
             }
         }
-
-
     }
 
     fn getlk(&mut self,
-             _req: &Request,
+             req: &Request,
              _ino: u64,
-             _fh: u64,
-             _lock_owner: u64,
-             _start: u64,
-             _end: u64,
-             _typ: u32,
-             _pid: u32,
+             fh: u64,
+             lock_owner: u64,
+             start: u64,
+             end: u64,
+             typ: u32,
+             pid: u32,
              reply: ReplyLock) {
-        trace!("getlk(ino={:?})", _ino);
-        reply.error(ENOSYS);
+        trace!("getlk(ino={:?}, start={}, end={}, typ={})", _ino, start, end, typ);
+        let _caller = as_caller!(self, req, reply);
+        match self.locks.getlk(fh, lock_owner, &Lock::new(start, end, typ, pid)) {
+            Ok(conflict) => reply.locked(conflict.start, conflict.end, conflict.typ, conflict.pid),
+            Err(errno) => reply.error(errno),
+        }
     }
     fn setlk(&mut self,
-             _req: &Request,
+             req: &Request,
              _ino: u64,
-             _fh: u64,
-             _lock_owner: u64,
-             _start: u64,
-             _end: u64,
-             _typ: u32,
-             _pid: u32,
-             _sleep: bool,
+             fh: u64,
+             lock_owner: u64,
+             start: u64,
+             end: u64,
+             typ: u32,
+             pid: u32,
+             sleep: bool,
              reply: ReplyEmpty) {
-        trace!("setlk(ino={:?})", _ino);
-        reply.error(ENOSYS);
+        trace!("setlk(ino={:?}, start={}, end={}, typ={}, sleep={})",
+               _ino,
+               start,
+               end,
+               typ,
+               sleep);
+        let guard = as_caller!(self, req, reply);
+        let lock = Lock::new(start, end, typ, pid);
+        if sleep {
+            self.locks.setlk_wait(fh, lock_owner, lock, guard.caller().clone(), reply);
+            return;
+        }
+        match self.locks.setlk(fh, lock_owner, &lock) {
+            Ok(_) => reply.ok(),
+            Err(errno) => reply.error(errno),
+        }
     }
 }
 
@@ -813,6 +1722,105 @@ fn main() {
                 Err(_) => Err(format!("Error: {} is not a valid u16 number", value)),
             })
             .value_name("port"))
+        .arg(Arg::with_name("filter-xattr-namespaces")
+            .help("Hide trusted.* extended attributes from listxattr for non-root callers")
+            .long("filter-xattr-namespaces"))
+        .arg(Arg::with_name("quota-deem-statfs")
+            .help("Report the quota limit of a directory from statfs instead of the volume \
+                   capacity.  Needs features.quota-deem-statfs enabled on the volume")
+            .long("quota-deem-statfs"))
+        .arg(Arg::with_name("read-only")
+            .help("Mount read-only.  Anything that would change the volume fails with EROFS")
+            .long("read-only")
+            .short("r"))
+        .arg(Arg::with_name("root-mode")
+            .help("Permissions to show for the mount root, in octal [default: the volume \
+                   root's]")
+            .long("root-mode")
+            .takes_value(true)
+            .validator(|value| parse_mode(&value).map(|_| ()))
+            .value_name("mode"))
+        .arg(Arg::with_name("root-uid")
+            .help("Owner to show for the mount root [default: the volume root's]")
+            .long("root-uid")
+            .takes_value(true)
+            .validator(|value| match u32::from_str(&value) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(format!("Error: {} is not a valid uid", value)),
+            })
+            .value_name("uid"))
+        .arg(Arg::with_name("root-gid")
+            .help("Group to show for the mount root [default: the volume root's]")
+            .long("root-gid")
+            .takes_value(true)
+            .validator(|value| match u32::from_str(&value) {
+                Ok(_) => Ok(()),
+                Err(_) => Err(format!("Error: {} is not a valid gid", value)),
+            })
+            .value_name("gid"))
+        .arg(Arg::with_name("allow-other")
+            .conflicts_with("allow-root")
+            .help("Let other users access the mount.  Needs user_allow_other in \
+                   /etc/fuse.conf when not mounting as root")
+            .long("allow-other"))
+        .arg(Arg::with_name("allow-root")
+            .help("Let root access the mount as well as the user mounting it")
+            .long("allow-root"))
+        .arg(Arg::with_name("default-permissions")
+            .help("Have the kernel enforce permissions from the mode bits")
+            .long("default-permissions"))
+        .arg(Arg::with_name("fsname")
+            .help("Source shown by mount [default: <server>:/<volume>]")
+            .long("fsname")
+            .takes_value(true)
+            .value_name("name"))
+        .arg(Arg::with_name("subtype")
+            .default_value("glusterfs")
+            .help("Filesystem type shown by mount, after fuse.")
+            .long("subtype")
+            .takes_value(true)
+            .value_name("type"))
+        .arg(Arg::with_name("options")
+            .help("Extra mount options passed to the kernel as is")
+            .long("options")
+            .multiple(true)
+            .number_of_values(1)
+            .short("o")
+            .takes_value(true)
+            .value_name("options"))
+        .arg(Arg::with_name("attr-timeout")
+            .default_value("1")
+            .help("Seconds the kernel may cache file attributes")
+            .long("attr-timeout")
+            .takes_value(true)
+            .validator(|value| parse_timeout(&value).map(|_| ()))
+            .value_name("seconds"))
+        .arg(Arg::with_name("entry-timeout")
+            .default_value("1")
+            .help("Seconds the kernel may cache names it looked up")
+            .long("entry-timeout")
+            .takes_value(true)
+            .validator(|value| parse_timeout(&value).map(|_| ()))
+            .value_name("seconds"))
+        .arg(Arg::with_name("negative-timeout")
+            .default_value("0")
+            .help("Seconds the kernel may remember that a name doesn't exist")
+            .long("negative-timeout")
+            .takes_value(true)
+            .validator(|value| parse_timeout(&value).map(|_| ()))
+            .value_name("seconds"))
+        .arg(Arg::with_name("gid-timeout")
+            .default_value("300")
+            .help("Seconds the supplementary groups of a process are reused before being \
+                   read again")
+            .long("gid-timeout")
+            .takes_value(true)
+            .validator(|value| parse_timeout(&value).map(|_| ()))
+            .value_name("seconds"))
+        .arg(Arg::with_name("server-access")
+            .help("Ask the server to evaluate access() instead of checking the mode bits \
+                   locally, so ACLs are honored")
+            .long("server-access"))
         .arg(Arg::with_name("server")
             .default_value("localhost")
             .help("The GlusterD server to connect to")
@@ -829,11 +1837,39 @@ fn main() {
         .get_matches();
     let mountpoint = matches.value_of("mount").unwrap();
     trace!("mountpoint: {:?}", mountpoint);
+    let mut options = MountOptions::new(&mountpoint);
+    options.read_only = matches.is_present("read-only");
+    options.quota_deem_statfs = matches.is_present("quota-deem-statfs");
+    options.filter_xattr_namespaces = matches.is_present("filter-xattr-namespaces");
+    options.server_access = matches.is_present("server-access");
+    options.allow_other = matches.is_present("allow-other");
+    options.allow_root = matches.is_present("allow-root");
+    options.default_permissions = matches.is_present("default-permissions");
+    let default_fsname = format!("{}:/{}",
+                                 matches.value_of("server").unwrap(),
+                                 matches.value_of("volume").unwrap());
+    options.fsname = Some(matches.value_of("fsname").unwrap_or(&default_fsname));
+    options.subtype = matches.value_of("subtype");
+    options.raw_options = matches.values_of("options")
+        .map(|values| values.collect())
+        .unwrap_or_default();
     // These unwraps are safe because clap has validated the input
-    let _ = GlusterFilesystem::new(matches.value_of("volume").unwrap(),
-                                   matches.value_of("server").unwrap(),
-                                   u16::from_str(&matches.value_of("port").unwrap()).unwrap(),
-                                   MountOptions::new(&mountpoint))
+    options.attr_timeout = parse_timeout(matches.value_of("attr-timeout").unwrap()).unwrap();
+    options.entry_timeout = parse_timeout(matches.value_of("entry-timeout").unwrap()).unwrap();
+    options.negative_timeout = parse_timeout(matches.value_of("negative-timeout").unwrap())
         .unwrap();
-    trace!("unmounted");
+    options.gid_timeout = parse_timeout(matches.value_of("gid-timeout").unwrap()).unwrap();
+    options.root_mode = matches.value_of("root-mode").map(|mode| parse_mode(mode).unwrap());
+    options.root_uid = matches.value_of("root-uid").map(|uid| u32::from_str(uid).unwrap());
+    options.root_gid = matches.value_of("root-gid").map(|gid| u32::from_str(gid).unwrap());
+    match GlusterFilesystem::new(matches.value_of("volume").unwrap(),
+                                 matches.value_of("server").unwrap(),
+                                 u16::from_str(&matches.value_of("port").unwrap()).unwrap(),
+                                 options) {
+        Ok(_) => trace!("unmounted"),
+        Err(e) => {
+            eprintln!("Error: unable to mount {}: {}", matches.value_of("volume").unwrap(), e);
+            std::process::exit(1);
+        }
+    }
 }
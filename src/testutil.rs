@@ -0,0 +1,24 @@
+use fuse::{FileAttr, FileType};
+use time::Timespec;
+
+/// Attributes of a file owned by root, with `perm` 0o755 and every time at
+/// the epoch.  Tests override what they care about with `..attr(ino, kind)`.
+pub fn attr(ino: u64, kind: FileType) -> FileAttr {
+    let epoch = Timespec { sec: 0, nsec: 0 };
+    FileAttr {
+        ino: ino,
+        size: 0,
+        blocks: 0,
+        atime: epoch,
+        mtime: epoch,
+        ctime: epoch,
+        crtime: epoch,
+        kind: kind,
+        perm: 0o755,
+        nlink: 1,
+        uid: 0,
+        gid: 0,
+        rdev: 0,
+        flags: 0,
+    }
+}
@@ -0,0 +1,255 @@
+use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use gfapi_sys::glfs::Struct_glfs;
+use libc::{c_int, c_void, uint32_t};
+
+use error::last_errno;
+use inode::InodeStore;
+use volume::{glfs_object, object_gfid, Volume};
+
+// The event glfs_upcall_register is asked for, and the reason
+// glfs_upcall_get_reason gives for it
+const GLFS_EVENT_INODE_INVALIDATE: uint32_t = 0x1;
+const GLFS_UPCALL_INODE_INVALIDATE: c_int = 1;
+
+#[allow(non_camel_case_types)]
+enum glfs_upcall {}
+#[allow(non_camel_case_types)]
+enum glfs_upcall_inode {}
+
+#[allow(non_camel_case_types)]
+type glfs_upcall_cbk = extern "C" fn(upcall: *mut glfs_upcall, data: *mut c_void);
+
+#[link(name = "gfapi")]
+extern "C" {
+    // Not in gfapi-sys
+    fn glfs_upcall_register(fs: *mut Struct_glfs,
+                            event_list: uint32_t,
+                            cbk: glfs_upcall_cbk,
+                            data: *mut c_void)
+                            -> c_int;
+    fn glfs_upcall_get_reason(upcall: *mut glfs_upcall) -> c_int;
+    fn glfs_upcall_get_event(upcall: *mut glfs_upcall) -> *mut c_void;
+    fn glfs_upcall_inode_get_object(event: *mut glfs_upcall_inode) -> *mut glfs_object;
+    fn glfs_upcall_inode_get_pobject(event: *mut glfs_upcall_inode) -> *mut glfs_object;
+    fn glfs_upcall_inode_get_oldpobject(event: *mut glfs_upcall_inode) -> *mut glfs_object;
+    fn glfs_free(ptr: *mut c_void);
+}
+
+/// A change another client made, as reported by gluster's upcall translator
+/// (GLFS_UPCALL_INODE_INVALIDATE).
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upcall {
+    /// The file whose attributes, data or names changed
+    pub gfid: String,
+    /// Directories an entry for the file was added to or removed from, for
+    /// example both sides of a rename
+    pub parents: Vec<String>,
+}
+
+/// Queue of upcalls waiting to be applied to the inode store.
+///
+/// Upcalls arrive on gluster's threads while the inode store belongs to the
+/// FUSE session, so they're queued and applied before the cache is
+/// consulted.  Only our own cache is brought up to date: fuse 0.3 speaks a
+/// protocol too old to tell the kernel to drop what it cached, so that still
+/// waits for the attribute and entry timeouts.
+pub struct Upcalls {
+    queue: Arc<Mutex<VecDeque<Upcall>>>,
+}
+
+impl Upcalls {
+    pub fn new() -> Upcalls {
+        Upcalls { queue: Arc::new(Mutex::new(VecDeque::new())) }
+    }
+
+    /// Ask gluster to deliver its inode invalidations to this queue.  The
+    /// volume has to have features.cache-invalidation turned on for any to
+    /// be sent.
+    pub fn register(&self, volume: &Volume) -> Result<(), c_int> {
+        // gluster holds on to this for as long as the volume is mounted, so
+        // the reference is never given back
+        let data = Arc::into_raw(self.queue.clone()) as *mut c_void;
+        let ret = unsafe {
+            glfs_upcall_register(volume.glfs(), GLFS_EVENT_INODE_INVALIDATE, received, data)
+        };
+        if ret < 0 {
+            drop(unsafe { Arc::from_raw(data as *const Mutex<VecDeque<Upcall>>) });
+            return Err(last_errno());
+        }
+        Ok(())
+    }
+
+    /// Queue an upcall as if gluster had sent it
+    #[cfg(test)]
+    pub fn push(&self, upcall: Upcall) {
+        self.queue.lock().unwrap().push_back(upcall);
+    }
+
+    /// Apply every upcall received so far.  Returns how many there were.
+    pub fn apply_pending(&self, inodes: &mut InodeStore) -> usize {
+        let pending: Vec<Upcall> = self.queue.lock().unwrap().drain(..).collect();
+        for upcall in &pending {
+            trace!("upcall: {:?}", upcall);
+            apply(inodes, upcall);
+        }
+        pending.len()
+    }
+}
+
+/// The callback glfs_upcall_register is given.  It runs on a gluster thread
+/// and owns `upcall`.
+extern "C" fn received(upcall: *mut glfs_upcall, data: *mut c_void) {
+    let queue = unsafe { &*(data as *const Mutex<VecDeque<Upcall>>) };
+    if unsafe { glfs_upcall_get_reason(upcall) } == GLFS_UPCALL_INODE_INVALIDATE {
+        let event = unsafe { glfs_upcall_get_event(upcall) } as *mut glfs_upcall_inode;
+        match inode_upcall(event) {
+            Ok(upcall) => queue.lock().unwrap().push_back(upcall),
+            Err(errno) => debug!("upcall err: errno {}", errno),
+        }
+    }
+    // Also closes the objects the event carries
+    unsafe { glfs_free(upcall as *mut c_void) };
+}
+
+/// The gfids in an inode invalidation.  The parents are only there when an
+/// entry was added or removed, a rename carrying both the old and the new one.
+fn inode_upcall(event: *mut glfs_upcall_inode) -> Result<Upcall, c_int> {
+    let file = unsafe { glfs_upcall_inode_get_object(event) };
+    let parents = unsafe {
+        [glfs_upcall_inode_get_pobject(event), glfs_upcall_inode_get_oldpobject(event)]
+    };
+    let mut upcall = Upcall {
+        gfid: object_gfid(file)?,
+        parents: Vec::new(),
+    };
+    for parent in parents.iter().filter(|parent| !parent.is_null()) {
+        upcall.parents.push(object_gfid(*parent)?);
+    }
+    Ok(upcall)
+}
+
+/// Bring the inode store in line with an upcall.  The file and the
+/// directories involved are marked stale, and names the file had in those
+/// directories are dropped since they may no longer exist.  Files we never
+/// cached need nothing.
+pub fn apply(inodes: &mut InodeStore, upcall: &Upcall) {
+    let parents: Vec<u64> = upcall.parents
+        .iter()
+        .filter_map(|gfid| inodes.get_by_gfid(gfid).map(|inode| inode.attr.ino))
+        .collect();
+    for parent in &parents {
+        inodes.invalidate(*parent);
+    }
+
+    let ino = match inodes.get_by_gfid(&upcall.gfid) {
+        Some(inode) => inode.attr.ino,
+        None => return,
+    };
+    inodes.invalidate(ino);
+
+    let parent_paths: Vec<PathBuf> = parents.iter()
+        .filter_map(|parent| inodes.get(*parent).and_then(|inode| inode.path()))
+        .map(|path| path.to_path_buf())
+        .collect();
+    let stale_links: Vec<PathBuf> = inodes[ino]
+        .links
+        .iter()
+        .filter(|link| link.parent().map_or(false, |dir| parent_paths.iter().any(|p| p == dir)))
+        .cloned()
+        .collect();
+    for link in stale_links {
+        inodes.remove_path(link);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use fuse::{FileAttr, FileType};
+    use time::Timespec;
+
+    use inode::{Inode, InodeStore, ROOT_GFID};
+    use super::{Upcall, Upcalls};
+
+    const TTL: Timespec = Timespec { sec: 60, nsec: 0 };
+
+    fn attr(ino: u64, kind: FileType) -> FileAttr {
+        let epoch = Timespec { sec: 0, nsec: 0 };
+        FileAttr {
+            ino: ino,
+            size: 0,
+            blocks: 0,
+            atime: epoch,
+            mtime: epoch,
+            ctime: epoch,
+            crtime: epoch,
+            kind: kind,
+            perm: 0o644,
+            nlink: 1,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            flags: 0,
+        }
+    }
+
+    fn store() -> InodeStore {
+        let mut store = InodeStore::new(attr(1, FileType::Directory));
+        let mut dir = Inode::new("/dir", attr(2, FileType::Directory));
+        dir.gfid = Some("d1".to_string());
+        store.insert(dir);
+        let mut file = Inode::new("/dir/file", attr(3, FileType::RegularFile));
+        file.gfid = Some("f1".to_string());
+        store.insert(file);
+        store
+    }
+
+    fn upcall(gfid: &str, parents: &[&str]) -> Upcall {
+        Upcall {
+            gfid: gfid.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn attribute_change_marks_inode_stale() {
+        let mut store = store();
+        let upcalls = Upcalls::new();
+        upcalls.push(upcall("f1", &[]));
+
+        assert_eq!(upcalls.apply_pending(&mut store), 1);
+        assert!(!store[3].is_fresh(TTL));
+        assert!(store[2].is_fresh(TTL));
+        assert_eq!(store.get_by_path("/dir/file").map(|i| i.attr.ino), Some(3));
+    }
+
+    #[test]
+    fn entry_change_drops_the_name() {
+        let mut store = store();
+        store.lookup(3);
+        let upcalls = Upcalls::new();
+        upcalls.push(upcall("f1", &["d1"]));
+        upcalls.apply_pending(&mut store);
+
+        // The kernel still holds the file, but not under its old name
+        assert!(store.get_by_path("/dir/file").is_none());
+        assert!(store[3].links.is_empty());
+        assert!(!store[2].is_fresh(TTL));
+    }
+
+    #[test]
+    fn unknown_files_only_touch_their_parents() {
+        let mut store = store();
+        let upcalls = Upcalls::new();
+        upcalls.push(upcall("new", &[ROOT_GFID]));
+        upcalls.push(upcall("other", &[]));
+
+        assert_eq!(upcalls.apply_pending(&mut store), 2);
+        assert!(!store[1].is_fresh(TTL));
+        assert!(store[2].is_fresh(TTL));
+        assert_eq!(store.len(), 3);
+        assert_eq!(upcalls.apply_pending(&mut store), 0);
+    }
+}
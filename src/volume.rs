@@ -0,0 +1,408 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use gfapi_sys::glfs::{glfs_from_glfd, glfs_getxattr, glfs_listxattr, Struct_glfs,
+                      Struct_glfs_fd};
+use gfapi_sys::gluster::Gluster;
+use libc::{c_char, c_int, c_uchar, c_void, dev_t, mode_t, size_t, ssize_t, stat, statvfs, EINVAL,
+           ERANGE};
+
+use error::{last_errno, ToFd};
+
+// Length of the handle glfs_h_extract_handle fills in, which is the gfid
+const GFAPI_HANDLE_LENGTH: usize = 16;
+
+#[allow(non_camel_case_types)]
+pub enum glfs_object {}
+
+#[link(name = "gfapi")]
+extern "C" {
+    // Commented out in gfapi-sys
+    fn glfs_statvfs(fs: *mut Struct_glfs, path: *const c_char, buf: *mut statvfs) -> c_int;
+    // The object handle API isn't in gfapi-sys at all
+    fn glfs_h_lookupat(fs: *mut Struct_glfs,
+                       parent: *mut glfs_object,
+                       path: *const c_char,
+                       stat: *mut stat,
+                       follow: c_int)
+                       -> *mut glfs_object;
+    fn glfs_h_stat(fs: *mut Struct_glfs, object: *mut glfs_object, stat: *mut stat) -> c_int;
+    fn glfs_h_open(fs: *mut Struct_glfs, object: *mut glfs_object, flags: c_int)
+                   -> *mut Struct_glfs_fd;
+    fn glfs_h_mkdir(fs: *mut Struct_glfs,
+                    parent: *mut glfs_object,
+                    name: *const c_char,
+                    mode: mode_t,
+                    stat: *mut stat)
+                    -> *mut glfs_object;
+    fn glfs_h_mknod(fs: *mut Struct_glfs,
+                    parent: *mut glfs_object,
+                    name: *const c_char,
+                    mode: mode_t,
+                    dev: dev_t,
+                    stat: *mut stat)
+                    -> *mut glfs_object;
+    fn glfs_h_symlink(fs: *mut Struct_glfs,
+                      parent: *mut glfs_object,
+                      name: *const c_char,
+                      data: *const c_char,
+                      stat: *mut stat)
+                      -> *mut glfs_object;
+    fn glfs_h_unlink(fs: *mut Struct_glfs, parent: *mut glfs_object, name: *const c_char)
+                     -> c_int;
+    fn glfs_h_rename(fs: *mut Struct_glfs,
+                     old_dir: *mut glfs_object,
+                     old_name: *const c_char,
+                     new_dir: *mut glfs_object,
+                     new_name: *const c_char)
+                     -> c_int;
+    fn glfs_h_link(fs: *mut Struct_glfs,
+                   target: *mut glfs_object,
+                   parent: *mut glfs_object,
+                   name: *const c_char)
+                   -> c_int;
+    fn glfs_h_readlink(fs: *mut Struct_glfs,
+                       object: *mut glfs_object,
+                       buf: *mut c_char,
+                       size: size_t)
+                       -> c_int;
+    fn glfs_h_access(fs: *mut Struct_glfs, object: *mut glfs_object, mask: c_int) -> c_int;
+    fn glfs_h_extract_handle(object: *mut glfs_object, handle: *mut c_uchar, len: c_int) -> c_int;
+    fn glfs_h_close(object: *mut glfs_object) -> c_int;
+}
+
+/// gfapi's handle on a file.  Unlike a path it keeps reaching the same file
+/// when it, or a directory above it, is renamed.  Closed when dropped.
+#[derive(Debug)]
+pub struct Object {
+    object: *mut glfs_object,
+}
+
+impl Object {
+    /// Take ownership of what a glfs_h_* call returned, null meaning it failed
+    fn from_raw(object: *mut glfs_object) -> Result<Object, c_int> {
+        if object.is_null() {
+            Err(last_errno())
+        } else {
+            Ok(Object { object: object })
+        }
+    }
+
+    /// The file's gfid, in the form /.gfid paths use
+    pub fn gfid(&self) -> Result<String, c_int> {
+        object_gfid(self.object)
+    }
+}
+
+/// The gfid of an object gfapi handed out, such as one an upcall carries
+pub fn object_gfid(object: *mut glfs_object) -> Result<String, c_int> {
+    if object.is_null() {
+        return Err(EINVAL);
+    }
+    let mut gfid = [0u8; GFAPI_HANDLE_LENGTH];
+    let ret =
+        unsafe { glfs_h_extract_handle(object, gfid.as_mut_ptr(), GFAPI_HANDLE_LENGTH as c_int) };
+    if ret < 0 {
+        return Err(last_errno());
+    }
+    Ok(format_gfid(&gfid))
+}
+
+impl Drop for Object {
+    fn drop(&mut self) {
+        unsafe { glfs_h_close(self.object) };
+    }
+}
+
+/// gfapi calls that gfapi-sys doesn't wrap, or wraps in a way that can't be
+/// used.
+///
+/// These need the raw glfs_t, which `Gluster` keeps to itself.  It's taken
+/// from a handle on the volume root that stays open as long as the mount, and
+/// glfs_fini cleans that handle up along with the rest.
+pub struct Volume {
+    fs: *mut Struct_glfs,
+}
+
+impl Volume {
+    pub fn new(handle: &Gluster) -> Result<Volume, c_int> {
+        let root: *mut Struct_glfs_fd = handle.opendir(Path::new("/")).fd("volume root")?;
+        Ok(Volume { fs: unsafe { glfs_from_glfd(root) } })
+    }
+
+    /// The raw glfs_t, for calls made outside this module
+    pub fn glfs(&self) -> *mut Struct_glfs {
+        self.fs
+    }
+
+    /// statvfs(3) of the filesystem `path` is on
+    pub fn statvfs(&self, path: &Path) -> Result<statvfs, c_int> {
+        let path = c_path(path)?;
+        let mut buf: statvfs = unsafe { mem::zeroed() };
+        let ret = unsafe { glfs_statvfs(self.fs, path.as_ptr(), &mut buf) };
+        if ret < 0 { Err(last_errno()) } else { Ok(buf) }
+    }
+
+    /// Look up `path`, relative to the directory `parent` or else to the
+    /// volume root, and lstat it in the same round trip
+    pub fn lookup(&self, parent: Option<&Object>, path: &Path) -> Result<(Object, stat), c_int> {
+        let path = c_path(path)?;
+        let parent = parent.map_or(ptr::null_mut(), |parent| parent.object);
+        let mut buf: stat = unsafe { mem::zeroed() };
+        // follow=0, so a symlink is looked up rather than what it points at
+        let object = unsafe { glfs_h_lookupat(self.fs, parent, path.as_ptr(), &mut buf, 0) };
+        Ok((Object::from_raw(object)?, buf))
+    }
+
+    /// lstat the file behind `object`
+    pub fn stat(&self, object: &Object) -> Result<stat, c_int> {
+        let mut buf: stat = unsafe { mem::zeroed() };
+        let ret = unsafe { glfs_h_stat(self.fs, object.object, &mut buf) };
+        if ret < 0 { Err(last_errno()) } else { Ok(buf) }
+    }
+
+    pub fn open(&self, object: &Object, flags: c_int) -> Result<*mut Struct_glfs_fd, c_int> {
+        let fd = unsafe { glfs_h_open(self.fs, object.object, flags) };
+        if fd.is_null() { Err(last_errno()) } else { Ok(fd) }
+    }
+
+    pub fn mkdir(&self, parent: &Object, name: &OsStr, mode: mode_t)
+                 -> Result<(Object, stat), c_int> {
+        let name = c_name(name)?;
+        let mut buf: stat = unsafe { mem::zeroed() };
+        let object = unsafe { glfs_h_mkdir(self.fs, parent.object, name.as_ptr(), mode, &mut buf) };
+        Ok((Object::from_raw(object)?, buf))
+    }
+
+    pub fn mknod(&self, parent: &Object, name: &OsStr, mode: mode_t, dev: dev_t)
+                 -> Result<(Object, stat), c_int> {
+        let name = c_name(name)?;
+        let mut buf: stat = unsafe { mem::zeroed() };
+        let object = unsafe {
+            glfs_h_mknod(self.fs, parent.object, name.as_ptr(), mode, dev, &mut buf)
+        };
+        Ok((Object::from_raw(object)?, buf))
+    }
+
+    /// Create `name` in `parent` as a symlink pointing at `target`
+    pub fn symlink(&self, parent: &Object, name: &OsStr, target: &Path)
+                   -> Result<(Object, stat), c_int> {
+        let name = c_name(name)?;
+        let target = c_path(target)?;
+        let mut buf: stat = unsafe { mem::zeroed() };
+        let object = unsafe {
+            glfs_h_symlink(self.fs, parent.object, name.as_ptr(), target.as_ptr(), &mut buf)
+        };
+        Ok((Object::from_raw(object)?, buf))
+    }
+
+    /// Remove `name` from `parent`.  gfapi rmdirs it if it's a directory, so
+    /// this serves both unlink and rmdir.  The kernel has already checked
+    /// that the entry is of the kind the caller asked to remove.
+    pub fn unlink(&self, parent: &Object, name: &OsStr) -> Result<(), c_int> {
+        let name = c_name(name)?;
+        let ret = unsafe { glfs_h_unlink(self.fs, parent.object, name.as_ptr()) };
+        if ret < 0 { Err(last_errno()) } else { Ok(()) }
+    }
+
+    pub fn rename(&self,
+                  old_dir: &Object,
+                  old_name: &OsStr,
+                  new_dir: &Object,
+                  new_name: &OsStr)
+                  -> Result<(), c_int> {
+        let old_name = c_name(old_name)?;
+        let new_name = c_name(new_name)?;
+        let ret = unsafe {
+            glfs_h_rename(self.fs,
+                          old_dir.object,
+                          old_name.as_ptr(),
+                          new_dir.object,
+                          new_name.as_ptr())
+        };
+        if ret < 0 { Err(last_errno()) } else { Ok(()) }
+    }
+
+    /// Give the file behind `target` another name, `name` in `parent`
+    pub fn link(&self, target: &Object, parent: &Object, name: &OsStr) -> Result<(), c_int> {
+        let name = c_name(name)?;
+        let ret = unsafe { glfs_h_link(self.fs, target.object, parent.object, name.as_ptr()) };
+        if ret < 0 { Err(last_errno()) } else { Ok(()) }
+    }
+
+    /// Read a symlink's target into `buf`, returning its length
+    pub fn readlink(&self, object: &Object, buf: &mut [u8]) -> Result<usize, c_int> {
+        let ret = unsafe {
+            glfs_h_readlink(self.fs, object.object, buf.as_mut_ptr() as *mut c_char, buf.len())
+        };
+        if ret < 0 { Err(last_errno()) } else { Ok(ret as usize) }
+    }
+
+    pub fn access(&self, object: &Object, mask: c_int) -> Result<(), c_int> {
+        let ret = unsafe { glfs_h_access(self.fs, object.object, mask) };
+        if ret < 0 { Err(last_errno()) } else { Ok(()) }
+    }
+
+    /// The value of the xattr `name` on `path`.  gfapi-sys's getxattr hands
+    /// gluster a zero sized buffer and returns whatever memory was behind it.
+    pub fn getxattr(&self, path: &Path, name: &OsStr) -> Result<Vec<u8>, c_int> {
+        let path = c_path(path)?;
+        let name = CString::new(name.as_bytes()).map_err(|_| EINVAL)?;
+        read_sized(|buf, size| unsafe {
+            glfs_getxattr(self.fs, path.as_ptr(), name.as_ptr(), buf, size)
+        })
+    }
+
+    /// Names of the xattrs on `path`
+    pub fn listxattr(&self, path: &Path) -> Result<Vec<OsString>, c_int> {
+        let path = c_path(path)?;
+        let list = read_sized(|buf, size| unsafe {
+            glfs_listxattr(self.fs, path.as_ptr(), buf, size)
+        })?;
+        Ok(split_names(&list))
+    }
+}
+
+/// Make a call that fills a buffer the way the xattr calls do, where a size
+/// of 0 asks how big the buffer has to be.  The value can grow between asking
+/// and reading, which comes back as ERANGE and means asking again.
+fn read_sized<F>(call: F) -> Result<Vec<u8>, c_int>
+    where F: Fn(*mut c_void, size_t) -> ssize_t
+{
+    loop {
+        let size = call(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(last_errno());
+        }
+        let mut buf: Vec<u8> = vec![0; size as usize];
+        let len = call(buf.as_mut_ptr() as *mut c_void, buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let errno = last_errno();
+        if errno != ERANGE {
+            return Err(errno);
+        }
+    }
+}
+
+/// A gfid in the canonical text form /.gfid paths use
+fn format_gfid(gfid: &[u8; GFAPI_HANDLE_LENGTH]) -> String {
+    let hex: Vec<String> = gfid.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("{}-{}-{}-{}-{}",
+            hex[..4].concat(),
+            hex[4..6].concat(),
+            hex[6..8].concat(),
+            hex[8..10].concat(),
+            hex[10..].concat())
+}
+
+/// Split a listxattr buffer, which holds the names back to back each nul
+/// terminated.
+fn split_names(list: &[u8]) -> Vec<OsString> {
+    list.split(|b| *b == 0)
+        .filter(|name| !name.is_empty())
+        .map(|name| OsStr::from_bytes(name).to_os_string())
+        .collect()
+}
+
+fn c_path(path: &Path) -> Result<CString, c_int> {
+    c_name(path.as_os_str())
+}
+
+fn c_name(name: &OsStr) -> Result<CString, c_int> {
+    CString::new(name.as_bytes()).map_err(|_| EINVAL)
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::ffi::OsString;
+    use std::ptr;
+
+    use libc::{c_void, size_t, ssize_t, ENODATA, ERANGE};
+
+    use super::{format_gfid, read_sized, split_names};
+
+    fn set_errno(errno: i32) {
+        unsafe {
+            *::libc::__errno_location() = errno;
+        }
+    }
+
+    /// Stands in for glfs_getxattr on a value that reads as `values[n]` on
+    /// the nth call, so it can change between the size probe and the read.
+    struct Xattr {
+        values: Vec<&'static [u8]>,
+        calls: Cell<usize>,
+    }
+
+    impl Xattr {
+        fn new(values: Vec<&'static [u8]>) -> Xattr {
+            Xattr {
+                values: values,
+                calls: Cell::new(0),
+            }
+        }
+
+        fn get(&self, buf: *mut c_void, size: size_t) -> ssize_t {
+            let value = self.values[self.calls.get().min(self.values.len() - 1)];
+            self.calls.set(self.calls.get() + 1);
+            if size == 0 {
+                return value.len() as ssize_t;
+            }
+            if value.len() > size {
+                set_errno(ERANGE);
+                return -1;
+            }
+            unsafe { ptr::copy_nonoverlapping(value.as_ptr(), buf as *mut u8, value.len()) };
+            value.len() as ssize_t
+        }
+    }
+
+    #[test]
+    fn value_is_read_at_its_probed_size() {
+        let xattr = Xattr::new(vec![b"user.a\0"]);
+        assert_eq!(read_sized(|buf, size| xattr.get(buf, size)),
+                   Ok(b"user.a\0".to_vec()));
+        assert_eq!(xattr.calls.get(), 2);
+    }
+
+    #[test]
+    fn value_that_grows_is_probed_again() {
+        let xattr = Xattr::new(vec![b"short", b"much longer"]);
+        assert_eq!(read_sized(|buf, size| xattr.get(buf, size)),
+                   Ok(b"much longer".to_vec()));
+        assert_eq!(xattr.calls.get(), 4);
+    }
+
+    #[test]
+    fn failures_carry_their_errno() {
+        let result = read_sized(|_, _| {
+            set_errno(ENODATA);
+            -1
+        });
+        assert_eq!(result, Err(ENODATA));
+    }
+
+    #[test]
+    fn gfids_format_like_uuids() {
+        let mut root = [0u8; 16];
+        root[15] = 1;
+        assert_eq!(format_gfid(&root), "00000000-0000-0000-0000-000000000001");
+        let gfid = [0x8a, 0x3f, 0x1c, 0x02, 0xd4, 0x5e, 0x4b, 0x7a, 0x9c, 0x10, 0xee, 0x01, 0x23,
+                    0x45, 0x67, 0x89];
+        assert_eq!(format_gfid(&gfid), "8a3f1c02-d45e-4b7a-9c10-ee0123456789");
+    }
+
+    #[test]
+    fn names_split_on_nul() {
+        let names: Vec<OsString> = vec!["user.a".into(), "trusted.b".into()];
+        assert_eq!(split_names(b"user.a\0trusted.b\0"), names);
+        assert!(split_names(b"").is_empty());
+    }
+}